@@ -1,62 +1,76 @@
 use crate::{
     channel::{
-        manifest::{Artefact, Manifest, PackageData},
+        manifest::{Archive, Artefact, Compression, Manifest, PackageData},
         Channel,
     },
     digest::Sha256,
     download::{self, Downloader},
     extension::{Path as PathExtension, Url as UrlExtension},
+    index::Index,
+    lock::LOCK_FILE,
+    pattern::Patterns,
+    signature::{self, Keyring, Signer},
+    snapshot::{CURRENT_LINK, SNAPSHOT_DIRECTORY},
 };
 use ahash::{AHashMap, AHashSet};
 use chrono::NaiveDate;
 use futures::{stream, StreamExt, TryStreamExt};
 use itertools::Itertools;
+use serde::Serialize;
 use std::{
+    collections::BTreeMap,
     error::Error,
     fmt::{self, Display, Formatter},
     io,
     num::NonZeroUsize,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 use tokio::{fs, task};
-use tracing::{info, info_span};
+use tracing::{info, info_span, warn};
 use tracing_futures::Instrument;
 use url::Url;
 use walkdir::WalkDir;
 
-trait PathExt {
-    /// Returns a relative version of the path. The return value is the same if the path is already
-    /// relative.
-    fn as_relative(&self) -> &Path;
-}
-
-impl PathExt for Path {
-    #[inline]
-    #[must_use]
-    fn as_relative(&self) -> &Path {
-        if !self.starts_with("/") {
-            return self;
-        }
-
-        self.strip_prefix("/").expect("path is not absolute")
-    }
-}
-
 #[derive(Debug)]
 pub enum BuildError {
     BadChecksum(Url),
+    BadManifest(Url, toml::de::Error),
+    BadManifestChecksum(Url),
+    BadManifestChecksumFile(Url),
+    BadManifestSignature(Url, signature::Error),
     BadOverlap,
     Download(download::Error),
     FileSystem(io::Error),
+    MissingManifestChecksum(Url),
+    MissingManifestSignature(Url),
+    Signature(signature::Error),
+    UnknownPackage(String),
+    UnknownProfile(String),
 }
 
 impl Display for BuildError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Self::BadChecksum(url) => write!(f, "bad checksum for '{}'", url),
+            Self::BadChecksum(url) => write!(f, "bad checksum for '{url}'"),
+            Self::BadManifest(url, error) => write!(f, "bad manifest '{url}': {error}"),
+            Self::BadManifestChecksum(url) => write!(f, "bad checksum for manifest '{url}'"),
+            Self::BadManifestChecksumFile(url) => write!(f, "bad checksum file '{url}'"),
+            Self::BadManifestSignature(url, error) => {
+                write!(f, "bad signature for manifest '{url}': {error}")
+            }
             Self::BadOverlap => write!(f, "channels have different overlapping files"),
             Self::Download(error) => error.fmt(f),
             Self::FileSystem(error) => error.fmt(f),
+            Self::MissingManifestChecksum(url) => {
+                write!(f, "missing checksum file for manifest '{url}'")
+            }
+            Self::MissingManifestSignature(url) => {
+                write!(f, "missing signature for manifest '{url}'")
+            }
+            Self::Signature(error) => write!(f, "failed to sign manifest: {error}"),
+            Self::UnknownPackage(package) => write!(f, "unknown package '{package}'"),
+            Self::UnknownProfile(profile) => write!(f, "unknown profile '{profile}'"),
         }
     }
 }
@@ -64,7 +78,16 @@ impl Display for BuildError {
 impl Error for BuildError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            Self::BadChecksum(_) | Self::BadOverlap => None,
+            Self::BadChecksum(_)
+            | Self::BadManifestChecksum(_)
+            | Self::BadManifestChecksumFile(_)
+            | Self::BadOverlap
+            | Self::MissingManifestChecksum(_)
+            | Self::MissingManifestSignature(_)
+            | Self::UnknownPackage(_)
+            | Self::UnknownProfile(_) => None,
+            Self::BadManifest(_, error) => error.source(),
+            Self::BadManifestSignature(_, error) | Self::Signature(error) => error.source(),
             Self::Download(error) => error.source(),
             Self::FileSystem(error) => error.source(),
         }
@@ -83,17 +106,296 @@ impl From<io::Error> for BuildError {
     }
 }
 
+impl From<signature::Error> for BuildError {
+    fn from(error: signature::Error) -> Self {
+        Self::Signature(error)
+    }
+}
+
+/// The directory, relative to the cache, where state belonging to the tool is kept.
+pub const STATE_DIRECTORY: &str = ".rustdown";
+
+/// The file, relative to the cache, that contains patterns describing paths that are never pruned.
+const IGNORE_FILE: &str = ".rustdownignore";
+
+/// The directory, relative to the cache, where partial downloads are kept until they're complete.
+const STAGING_DIRECTORY: &str = ".rustdown/staging";
+
+/// The file, relative to the cache, where the index of archive checksums is kept.
+pub const INDEX_FILE: &str = ".rustdown/index.json";
+
+/// The directory, relative to the cache, where archives are kept by checksum in the blob store.
+const BLOB_DIRECTORY: &str = "blobs/sha256";
+
+/// Describes how channel manifests are verified before they're trusted.
+#[derive(Clone, Debug, Default)]
+pub struct Verification {
+    /// Whether or not manifests are verified using the checksum files that accompany them.
+    pub checksums: bool,
+    /// The keyring used to verify the signatures that accompany manifests. Signatures are not
+    /// verified when this is `None`.
+    pub keyring: Option<Keyring>,
+}
+
+/// Describes how archives in the blob store are linked into the `dist` tree.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Link {
+    Hard,
+    Symbolic,
+}
+
+/// Describes how a cache is maintained.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Whether or not checksum files are written alongside archives. Checksum files are always
+    /// written alongside manifests.
+    pub archive_checksums: bool,
+    /// The signer used to write the detached signatures that accompany manifests. Signatures are
+    /// not written when this is `None`.
+    pub signer: Option<Signer>,
+    /// Whether or not files are only added to the cache. Files that aren't tracked by the channels
+    /// being cached are not pruned when this is true.
+    pub additive: bool,
+    /// Patterns describing paths that are never pruned in addition to the patterns in the ignore
+    /// file of the cache.
+    pub protected: Vec<String>,
+    /// Whether or not archives are always rehashed. Otherwise, archives are only rehashed if they
+    /// have changed since they were last hashed.
+    pub paranoid: bool,
+    /// How archives in the blob store are linked into the `dist` tree. Archives are kept in the
+    /// `dist` tree when this is `None`.
+    pub blobs: Option<Link>,
+}
+
+/// The package that describes the components of a toolchain.
+const TOOLCHAIN_PACKAGE: &str = "rust";
+
+/// Describes the parts of channel manifests that are cached.
+#[derive(Clone, Debug, Default)]
+pub struct Selection {
+    /// The targets to cache. Every target is cached when this is `None`.
+    pub targets: Option<AHashSet<String>>,
+    /// The packages to cache in addition to the packages in `profile`.
+    pub packages: Option<AHashSet<String>>,
+    /// The profile to cache. Every package is cached when this and `packages` are `None`.
+    pub profile: Option<String>,
+    /// The compression formats to cache. Every format is cached when this is `None`.
+    pub compressions: Option<AHashSet<Compression>>,
+}
+
+impl Selection {
+    /// Returns the packages in `manifest` that are cached or `None` if every package is cached.
+    ///
+    /// Renamed packages are resolved using the renames in `manifest`. The components and extensions
+    /// of cached packages are also cached so that clients can install them.
+    fn packages(&self, manifest: &Manifest) -> Result<Option<AHashSet<String>>, BuildError> {
+        if self.packages.is_none() && self.profile.is_none() {
+            return Ok(None);
+        }
+
+        let profile = match &self.profile {
+            Some(profile) => manifest
+                .profiles
+                .get(profile)
+                .ok_or_else(|| BuildError::UnknownProfile(profile.clone()))?
+                .as_slice(),
+            None => &[],
+        };
+
+        let resolve = |package: &String| {
+            manifest
+                .renames
+                .get(package)
+                .map_or(package, |rename| &rename.to)
+                .clone()
+        };
+
+        let mut pending = self
+            .packages
+            .iter()
+            .flatten()
+            .map(|package| {
+                let resolved = resolve(package);
+                if manifest.packages.contains_key(&resolved) {
+                    Ok(resolved)
+                } else {
+                    Err(BuildError::UnknownPackage(package.clone()))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Profiles can describe packages that aren't in every manifest (eg. `rust-mingw`).
+        pending.extend(profile.iter().map(resolve));
+
+        let mut packages = AHashSet::new();
+        while let Some(package) = pending.pop() {
+            if packages.contains(&package) {
+                continue;
+            }
+
+            for (target, artefact) in manifest
+                .packages
+                .get(&package)
+                .into_iter()
+                .flat_map(|data| &data.artefacts)
+            {
+                if self.contains_target(target) {
+                    pending.extend(
+                        artefact
+                            .components
+                            .iter()
+                            .chain(&artefact.extensions)
+                            .map(|component| component.pkg.clone()),
+                    );
+                }
+            }
+
+            packages.insert(package);
+        }
+
+        Ok(Some(packages))
+    }
+
+    /// Returns whether or not artefacts for `target` are cached.
+    ///
+    /// Target independent artefacts (eg. `rust-src`) are always cached.
+    #[inline]
+    #[must_use]
+    fn contains_target(&self, target: &str) -> bool {
+        target == "*"
+            || self
+                .targets
+                .as_ref()
+                .is_none_or(|targets| targets.contains(target))
+    }
+
+    /// Returns whether or not archives compressed with `compression` are cached.
+    #[inline]
+    #[must_use]
+    fn contains_compression(&self, compression: &Compression) -> bool {
+        self.compressions
+            .as_ref()
+            .is_none_or(|compressions| compressions.contains(compression))
+    }
+}
+
+/// Describes the problems found while verifying a cache.
+///
+/// Paths are relative to the root of the cache.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Files that are referenced but don't exist.
+    pub missing: Vec<PathBuf>,
+    /// Files that don't match their checksum or can't be parsed.
+    pub corrupt: Vec<PathBuf>,
+    /// Files that aren't referenced.
+    pub unreferenced: Vec<PathBuf>,
+    /// Whether or not the cache doesn't contain any manifests.
+    pub empty: bool,
+}
+
+impl Report {
+    /// Returns the number of problems.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.missing.len() + self.corrupt.len() + self.unreferenced.len() + usize::from(self.empty)
+    }
+
+    /// Returns whether or not there are no problems.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn extend(&mut self, other: Self) {
+        self.missing.extend(other.missing);
+        self.corrupt.extend(other.corrupt);
+        self.unreferenced.extend(other.unreferenced);
+    }
+}
+
+/// Returns the checksum described by a checksum file.
+///
+/// Only the first token is considered so that both bare checksums and the format of `sha256sum` are
+/// supported.
+fn parse_checksum(bytes: &[u8]) -> Option<Sha256> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|checksum| checksum.split_whitespace().next())
+        .and_then(|checksum| Sha256::from_str(checksum).ok())
+}
+
+/// Returns whether or not a relative path is the path of a cached manifest or alias.
+fn is_manifest(path: &Path) -> bool {
+    path.starts_with("dist")
+        && path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("channel-rust-"))
+}
+
+/// Describes the changes that building a cache would make.
+///
+/// Paths are relative to the root of the cache and sizes are in bytes.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Files that would be deleted and their sizes.
+    pub delete: BTreeMap<PathBuf, u64>,
+    /// Archives that would be downloaded and their sizes. Sizes are requested from the server
+    /// because manifests don't describe them and are `None` if the server doesn't describe them
+    /// either.
+    pub download: BTreeMap<PathBuf, Option<u64>>,
+    /// Archives that exist but would be hashed to determine whether or not they're cached and their
+    /// sizes.
+    pub hash: BTreeMap<PathBuf, u64>,
+    /// Archives that are already cached and their sizes.
+    pub skip: BTreeMap<PathBuf, u64>,
+    /// Files that would be written and their sizes.
+    pub write: BTreeMap<PathBuf, u64>,
+}
+
+/// Describes what building a cache would do with an archive.
+enum Change {
+    Download(Option<u64>),
+    Hash(u64),
+    Skip(u64),
+}
+
+/// Describes the files that are referenced by the manifests in a cache.
+///
+/// Paths are relative to the root of the cache.
+#[derive(Debug, Default)]
+struct References {
+    /// Every referenced file including manifests and the files that accompany them.
+    files: AHashSet<PathBuf>,
+    /// Every referenced archive and its checksum.
+    archives: AHashMap<PathBuf, Option<Sha256>>,
+    /// Manifests that can't be parsed.
+    corrupt: Vec<PathBuf>,
+}
+
+/// Maps the relative path of an archive to its URL and checksum.
+type Archives<'a> = AHashMap<String, (&'a Url, Option<&'a Sha256>)>;
+
 pub struct Cache {
     path: PathBuf,
     host: Url,
+    options: Options,
 }
 
 impl Cache {
     /// Creates a cache from `path`.
     #[inline]
     #[must_use]
-    pub fn new(path: PathBuf, host: Url) -> Self {
-        Self { path, host }
+    pub fn new(path: PathBuf, host: Url, options: Options) -> Self {
+        Self {
+            path,
+            host,
+            options,
+        }
     }
 
     #[inline]
@@ -116,23 +418,48 @@ impl Cache {
         )
     }
 
-    /// Returns the relative manifest path for the channel.
+    /// Selects the parts of a manifest that are cached.
     ///
-    /// The official distribution server hosts a large number of stable manifest copies in unusual
-    /// places. These are not replicated here because it is not clear how they are used.
-    #[inline]
-    #[must_use]
-    fn relative_manifest_path(channel: &Channel, manifest: &Manifest) -> String {
-        let date = Self::date(channel, manifest).format("%Y-%m-%d");
-        match channel {
-            Channel::Stable(version) => {
-                format!("dist/channel-rust-{}.toml", version)
+    /// Packages that are not selected are removed along with references to them. The toolchain
+    /// package is always selected because clients use it to resolve the components of a toolchain.
+    /// Artefacts for targets that are not selected are marked as unavailable so that clients report
+    /// a clear error rather than failing to download them. Archives compressed with formats that are
+    /// not selected are removed and artefacts without any remaining archives are marked as
+    /// unavailable.
+    fn select_manifest(manifest: &Manifest, selection: &Selection) -> Result<Manifest, BuildError> {
+        let packages = selection.packages(manifest)?;
+        let mut manifest = manifest.clone();
+        if let Some(packages) = packages {
+            manifest
+                .packages
+                .retain(|package, _| package == TOOLCHAIN_PACKAGE || packages.contains(package));
+
+            let retained = manifest.packages.keys().cloned().collect::<AHashSet<_>>();
+            for data in manifest.packages.values_mut() {
+                for artefact in data.artefacts.values_mut() {
+                    artefact
+                        .components
+                        .retain(|component| retained.contains(&component.pkg));
+                    artefact
+                        .extensions
+                        .retain(|component| retained.contains(&component.pkg));
+                }
             }
+        }
 
-            Channel::DateBased { name, date: _ } => {
-                format!("dist/{}/channel-rust-{}.toml", date, name)
+        for data in manifest.packages.values_mut() {
+            for (target, artefact) in &mut data.artefacts {
+                artefact.archives.retain(|compression, _| {
+                    selection.contains_target(target) && selection.contains_compression(compression)
+                });
+
+                if artefact.archives.is_empty() {
+                    artefact.available = false;
+                }
             }
         }
+
+        Ok(manifest)
     }
 
     /// Normalises a manifest.
@@ -142,6 +469,7 @@ impl Cache {
     #[must_use]
     fn normalise_manifest(channel: &Channel, manifest: &Manifest, host: &Url) -> Manifest {
         Manifest {
+            version: manifest.version.clone(),
             date: Self::date(channel, manifest),
             packages: manifest
                 .packages
@@ -168,23 +496,77 @@ impl Cache {
 
                                         Artefact {
                                             available: artefact.available,
-                                            url: artefact.url.as_ref().map(transform),
-                                            hash: artefact.hash,
-                                            xz_url: artefact.xz_url.as_ref().map(transform),
-                                            xz_hash: artefact.xz_hash,
+                                            archives: artefact
+                                                .archives
+                                                .iter()
+                                                .map(|(compression, archive)| {
+                                                    (
+                                                        compression.clone(),
+                                                        Archive {
+                                                            url: transform(&archive.url),
+                                                            hash: archive.hash,
+                                                        },
+                                                    )
+                                                })
+                                                .collect(),
+                                            components: artefact.components.clone(),
+                                            extensions: artefact.extensions.clone(),
+                                            unknown: artefact.unknown.clone(),
                                         }
                                     })
                                 })
                                 .collect(),
+                            unknown: data.unknown.clone(),
                         },
                     )
                 })
                 .collect(),
+            renames: manifest.renames.clone(),
+            profiles: manifest.profiles.clone(),
+            unknown: manifest.unknown.clone(),
+            layout: manifest.layout.clone(),
         }
     }
 
-    /// Deletes files that should be preserved. Empty directories are removed.
+    /// Returns the patterns describing paths that are never pruned.
+    ///
+    /// The patterns in the options are combined with the patterns in the ignore file of the cache.
+    /// The ignore file and snapshots are always protected.
+    async fn protected(&self) -> Result<Patterns, io::Error> {
+        let contents = match fs::read_to_string(self.path.join(IGNORE_FILE)).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+
+        // Arguments take precedence over the ignore file. The ignore file and snapshots (including
+        // the links that point to them) can't be unprotected.
+        let fixed = [
+            format!("/{IGNORE_FILE}"),
+            format!("/{SNAPSHOT_DIRECTORY}/"),
+            format!("/{CURRENT_LINK}"),
+            format!("/.{CURRENT_LINK}.*.tmp"),
+        ];
+
+        Patterns::new(
+            contents
+                .lines()
+                .chain(self.options.protected.iter().map(String::as_str))
+                .chain(fixed.iter().map(String::as_str)),
+        )
+        .map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad pattern in '{IGNORE_FILE}': {error}"),
+            )
+        })
+    }
+
+    /// Deletes files that aren't preserved or protected. Empty directories are removed unless
+    /// they're protected.
     async fn prune(&self, preserve: AHashSet<PathBuf>) -> Result<(), io::Error> {
+        let protected = self.protected().await?;
+
         // There are no obvious ways to prune the cache in parallel without traversing twice. For
         // instance, the decision to remove a directory is determined by previous decisions.
         //
@@ -192,7 +574,7 @@ impl Cache {
         // synchronously deleting empty directories using a depth-first traversal.
         let root = self.path.clone();
         task::spawn_blocking(move || {
-            WalkDir::new(root)
+            WalkDir::new(&root)
                 // The contents are yielded first so that empty directories can be pruned.
                 .contents_first(true)
                 .into_iter()
@@ -201,13 +583,20 @@ impl Cache {
                         use std::fs;
 
                         let path = entry.path();
+                        if protected.matches(
+                            path.strip_prefix(&root).expect("path outside cache"),
+                            entry.file_type().is_dir(),
+                        ) {
+                            return Ok(());
+                        }
+
                         match entry.file_type() {
                             t if t.is_dir() => match fs::read_dir(path)?.next() {
                                 Some(_) => Ok(()),
                                 None => fs::remove_dir(path),
                             },
 
-                            t if t.is_file() => {
+                            t if t.is_file() || t.is_symlink() => {
                                 if preserve.contains(path) {
                                     Ok(())
                                 } else {
@@ -215,8 +604,6 @@ impl Cache {
                                 }
                             }
 
-                            t if t.is_symlink() => fs::remove_file(path),
-
                             _ => unreachable!(),
                         }
                     }
@@ -227,132 +614,608 @@ impl Cache {
         .expect("panicked while pruning cache")
     }
 
-    /// Builds a cache.
-    #[allow(clippy::too_many_lines)]
-    pub async fn build(
-        &self,
-        channels: &AHashMap<Channel, Manifest>,
+    /// Returns the path and contents of the checksum file that accompanies the file at `path`.
+    ///
+    /// The checksum file has the same path with an additional `.sha256` extension and uses the
+    /// format of `sha256sum`.
+    fn checksum_file(path: &Path, hash: &Sha256) -> (PathBuf, Vec<u8>) {
+        let name = path.file_name().expect("unnamed file").to_string_lossy();
+        (
+            path.with_suffix(".sha256"),
+            format!("{hash}  {name}\n").into_bytes(),
+        )
+    }
+
+    /// Writes the checksum file that accompanies the file at `path`.
+    async fn write_checksum(path: &Path, hash: &Sha256) -> Result<(), io::Error> {
+        let (path, contents) = Self::checksum_file(path, hash);
+        path.write_atomic(&contents).await
+    }
+
+    /// Verifies a manifest using the checksum file that accompanies it.
+    ///
+    /// The checksum file has the same URL as the manifest with an additional `.sha256` extension.
+    async fn verify_manifest_checksum(
+        url: &Url,
+        bytes: &[u8],
         downloader: &Downloader,
-        jobs: NonZeroUsize,
     ) -> Result<(), BuildError> {
-        // Verify that there are no overlapping files with different checksums.
-        let archives = channels
+        let checksum_url = url.with_path_suffix(".sha256");
+        let checksum = match downloader.fetch(checksum_url.clone()).await {
+            Ok(checksum) => checksum,
+            Err(error) if error.is_not_found() => {
+                return Err(BuildError::MissingManifestChecksum(url.clone()))
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let expected =
+            parse_checksum(&checksum).ok_or(BuildError::BadManifestChecksumFile(checksum_url))?;
+
+        if Sha256::from_slice(bytes) != expected {
+            return Err(BuildError::BadManifestChecksum(url.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Verifies a manifest using the detached signature that accompanies it.
+    ///
+    /// The signature has the same URL as the manifest with an additional `.asc` extension.
+    async fn verify_manifest_signature(
+        url: &Url,
+        bytes: &[u8],
+        keyring: &Keyring,
+        downloader: &Downloader,
+    ) -> Result<(), BuildError> {
+        let signature = match downloader.fetch(url.with_path_suffix(".asc")).await {
+            Ok(signature) => signature,
+            Err(error) if error.is_not_found() => {
+                return Err(BuildError::MissingManifestSignature(url.clone()))
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        keyring
+            .verify(bytes, &signature)
+            .map_err(|error| BuildError::BadManifestSignature(url.clone(), error))
+    }
+
+    /// Fetches the manifest of every channel from its URL.
+    pub async fn fetch_manifests(
+        channels: &AHashMap<Channel, Url>,
+        verification: &Verification,
+        downloader: &Downloader,
+        jobs: NonZeroUsize,
+    ) -> Result<AHashMap<Channel, Manifest>, BuildError> {
+        stream::iter(channels)
+            .map(|(channel, url)| async move {
+                let bytes = downloader.fetch(url.clone()).await?;
+                if verification.checksums {
+                    Self::verify_manifest_checksum(url, &bytes, downloader).await?;
+                }
+
+                if let Some(keyring) = &verification.keyring {
+                    Self::verify_manifest_signature(url, &bytes, keyring, downloader).await?;
+                }
+
+                let manifest = Manifest::from_slice(&bytes)
+                    .map_err(|error| BuildError::BadManifest(url.clone(), error))?;
+
+                info!(channel = channel.to_string().as_str(), "fetched manifest");
+                Ok::<_, BuildError>((channel.clone(), manifest))
+            })
+            .map(Ok)
+            .try_buffer_unordered(jobs.get())
+            .try_collect()
+            .await
+    }
+
+    /// Fetches the manifest of every channel and selects the parts that are cached.
+    async fn select_manifests(
+        channels: &AHashMap<Channel, Url>,
+        verification: &Verification,
+        selection: &Selection,
+        downloader: &Downloader,
+        jobs: NonZeroUsize,
+    ) -> Result<AHashMap<Channel, Manifest>, BuildError> {
+        Self::fetch_manifests(channels, verification, downloader, jobs)
+            .await?
+            .into_iter()
+            .map(|(channel, manifest)| {
+                let manifest = Self::select_manifest(&manifest, selection)?;
+                Ok((channel, manifest))
+            })
+            .collect()
+    }
+
+    /// Returns the relative path of every unique archive along with its URL and checksum.
+    ///
+    /// Channels can share archives but only if they have the same checksum. Shared archives are
+    /// only included once so that they're only downloaded once.
+    fn archives(channels: &AHashMap<Channel, Manifest>) -> Result<Archives<'_>, BuildError> {
+        channels
             .iter()
             .flat_map(|(channel, manifest)| {
-                manifest.archives().map(|(archive, checksum)| {
+                manifest.archives().map(|(url, checksum)| {
                     (
                         Self::relative_archive_path(
                             channel,
                             manifest,
-                            archive.file_name().expect("unnamed archive"),
+                            url.file_name().expect("unnamed archive"),
                         ),
-                        checksum,
+                        (url, checksum),
                     )
                 })
             })
-            .try_fold(AHashMap::new(), |mut paths, (path, checksum)| {
-                if let Some(found) = paths.insert(path, checksum) {
+            .try_fold(AHashMap::new(), |mut paths, (path, (url, checksum))| {
+                if let Some((_, found)) = paths.insert(path, (url, checksum)) {
                     if checksum != found {
                         return Err(BuildError::BadOverlap);
                     }
                 }
 
                 Ok(paths)
-            })?;
+            })
+    }
+
+    /// Returns the cached manifest of every channel that isn't in `channels`.
+    async fn cached_manifests(
+        &self,
+        channels: &AHashMap<Channel, Manifest>,
+    ) -> Result<AHashMap<Channel, Vec<u8>>, io::Error> {
+        let mut manifests = AHashMap::new();
+        if !self.path.async_try_exists().await? {
+            return Ok(manifests);
+        }
+
+        for path in self.files().await? {
+            let channel = path
+                .to_str()
+                .and_then(Channel::from_relative_manifest_path)
+                .filter(|channel| !channels.contains_key(channel));
+
+            if let Some(channel) = channel {
+                manifests.insert(channel, fs::read(self.path.join(&path)).await?);
+            }
+        }
+
+        Ok(manifests)
+    }
+
+    /// Returns the relative path and contents of every normalised manifest and alias along with
+    /// the checksum files and signatures that accompany them.
+    ///
+    /// The newest channel with each name is aliased. Channels with manifests that are already
+    /// cached are included when files are only added to the cache. A signature has the same path
+    /// as the manifest with an additional `.asc` extension.
+    async fn manifest_files(
+        &self,
+        channels: &AHashMap<Channel, Manifest>,
+    ) -> Result<Vec<(PathBuf, Vec<u8>)>, BuildError> {
+        let cached = if self.options.additive {
+            self.cached_manifests(channels).await?
+        } else {
+            AHashMap::new()
+        };
+
+        let manifests = channels
+            .iter()
+            .map(|(channel, manifest)| {
+                (
+                    channel,
+                    Self::normalise_manifest(channel, manifest, &self.host).to_vec(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let aliases = manifests
+            .iter()
+            .map(|(channel, bytes)| (*channel, bytes))
+            .chain(&cached)
+            .sorted_by_key(|(channel, _)| *channel)
+            .group_by(|(channel, _)| channel.name())
+            .into_iter()
+            .map(|(name, group)| {
+                let (_, bytes) = group
+                    .max_by_key(|(channel, _)| *channel)
+                    .expect("missing associated channel");
+
+                (format!("dist/channel-rust-{name}.toml"), bytes)
+            })
+            .collect::<Vec<_>>();
+
+        manifests
+            .iter()
+            .map(|(channel, bytes)| (channel.relative_manifest_path(), bytes))
+            .chain(aliases)
+            .map(|(path, bytes)| {
+                let path = PathBuf::from(path);
+                let mut files = vec![Self::checksum_file(&path, &Sha256::from_slice(bytes))];
+                if let Some(signer) = &self.options.signer {
+                    files.push((path.with_suffix(".asc"), signer.sign(bytes)?));
+                }
+
+                files.push((path, bytes.clone()));
+                Ok(files)
+            })
+            .flatten_ok()
+            .collect()
+    }
+
+    /// Returns the relative path where the archive at the relative `path` is stored.
+    ///
+    /// Archives are stored in the blob store when it's enabled unless their checksum is unknown
+    /// because blobs are addressed by their checksum.
+    fn storage_path(&self, path: &str, hash: Option<&Sha256>) -> PathBuf {
+        match (self.options.blobs, hash) {
+            (Some(_), Some(hash)) => Path::new(BLOB_DIRECTORY).join(hash.to_string()),
+            _ => PathBuf::from(path),
+        }
+    }
+
+    /// Replaces the archive at the relative `path` with a link to the blob at the relative `blob`.
+    ///
+    /// The link is created at a temporary sibling and renamed into place so that the archive is
+    /// never missing. Symbolic links are relative so that the cache can be moved.
+    async fn link_blob(&self, path: &Path, blob: &Path, link: Link) -> Result<(), io::Error> {
+        let destination = self.path.join(path);
+        let temporary = destination.temporary();
+
+        // Renaming a hard link onto another hard link to the same file does nothing so the archive
+        // is left alone if it's already a hard link to the blob.
+        #[cfg(unix)]
+        if link == Link::Hard {
+            use std::os::unix::fs::MetadataExt;
+
+            if let Ok(metadata) = fs::symlink_metadata(&destination).await {
+                let blob = fs::metadata(self.path.join(blob)).await?;
+                if metadata.dev() == blob.dev() && metadata.ino() == blob.ino() {
+                    return Ok(());
+                }
+            }
+        }
+
+        // A temporary link might be left behind if a previous build was interrupted.
+        temporary.remove_file_if_exists().await?;
+
+        match link {
+            Link::Hard => fs::hard_link(self.path.join(blob), &temporary).await?,
+            Link::Symbolic => {
+                let target = path
+                    .parent()
+                    .expect("file has no parent")
+                    .components()
+                    .map(|_| Path::new(".."))
+                    .collect::<PathBuf>()
+                    .join(blob);
+
+                #[cfg(unix)]
+                fs::symlink(target, &temporary).await?;
+                #[cfg(windows)]
+                fs::symlink_file(target, &temporary).await?;
+            }
+        }
+
+        fs::rename(&temporary, &destination).await?;
+
+        // The temporary link is left behind if the rename did nothing because it was already linked
+        // to the same file as the archive.
+        temporary.remove_file_if_exists().await
+    }
+
+    /// Returns the relative paths that are preserved when the cache is pruned.
+    ///
+    /// Partial downloads of tracked archives are preserved so that they can be resumed. Blobs are
+    /// only preserved while a tracked archive refers to them.
+    fn preserved(
+        &self,
+        archives: &Archives<'_>,
+        files: &[(PathBuf, Vec<u8>)],
+    ) -> AHashSet<PathBuf> {
+        archives
+            .iter()
+            .flat_map(|(archive, (_, hash))| {
+                let path = PathBuf::from(archive);
+                let checksum = self
+                    .options
+                    .archive_checksums
+                    .then(|| path.with_suffix(".sha256"));
+
+                [
+                    self.storage_path(archive, *hash),
+                    path,
+                    Path::new(STAGING_DIRECTORY).join(archive),
+                ]
+                .into_iter()
+                .chain(checksum)
+            })
+            .chain(files.iter().map(|(path, _)| path.clone()))
+            .chain([PathBuf::from(INDEX_FILE), PathBuf::from(LOCK_FILE)])
+            .collect()
+    }
+
+    /// Returns whether or not the archive at the relative `path` is already cached.
+    ///
+    /// Archives without a checksum are never considered to be cached.
+    async fn is_cached(
+        &self,
+        index: &Index,
+        path: &Path,
+        hash: Option<&Sha256>,
+    ) -> Result<bool, io::Error> {
+        let Some(hash) = hash else {
+            return Ok(false);
+        };
+
+        Ok(index.hash(path, self.options.paranoid).await? == Some(*hash))
+    }
+
+    /// Reads the index of archive checksums.
+    async fn read_index(&self) -> Result<Index, io::Error> {
+        Index::read(self.path.clone(), &self.path.join(INDEX_FILE)).await
+    }
+
+    /// Writes the index of archive checksums.
+    ///
+    /// The checksums of every archive that still exists are kept so that the checksums of archives
+    /// that were added by other builds (eg. additive builds of other channels) aren't lost.
+    async fn write_index(&self, index: &Index) -> Result<(), io::Error> {
+        let path = self.path.join(INDEX_FILE);
+        index.retain_existing().await?;
+
+        fs::create_dir_all(path.parent().expect("file has no parent")).await?;
+        path.write_atomic(&index.to_vec()).await
+    }
+
+    /// Returns the changes that building a cache from the channel manifests at the given URLs
+    /// would make without making them.
+    ///
+    /// Manifests are fetched so that the archives can be determined but nothing is written.
+    pub async fn plan(
+        &self,
+        channels: &AHashMap<Channel, Url>,
+        verification: &Verification,
+        selection: &Selection,
+        downloader: &Downloader,
+        jobs: NonZeroUsize,
+    ) -> Result<Plan, BuildError> {
+        let channels =
+            Self::select_manifests(channels, verification, selection, downloader, jobs).await?;
+        let archives = Self::archives(&channels)?;
+        let files = self.manifest_files(&channels).await?;
+        let preserve = self.preserved(&archives, &files);
+
+        let mut plan = Plan::default();
+        if !self.options.additive && self.path.async_try_exists().await? {
+            let protected = self.protected().await?;
+            for path in self.files().await? {
+                if !preserve.contains(&path) && !protected.matches(&path, false) {
+                    let size = fs::symlink_metadata(self.path.join(&path)).await?.len();
+                    plan.delete.insert(path, size);
+                }
+            }
+        }
+
+        // Archives are only looked up in the index so that planning never hashes them.
+        let index = &self.read_index().await?;
+        let changes: Vec<(PathBuf, Change)> = stream::iter(&archives)
+            .map(|(archive, (url, hash))| async move {
+                // Archives that are cached in the `dist` tree are added to the blob store rather
+                // than downloaded.
+                let mut change = None;
+                for path in [self.storage_path(archive, *hash), PathBuf::from(archive)] {
+                    let size = match fs::metadata(self.path.join(&path)).await {
+                        Ok(metadata) => metadata.len(),
+                        Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                        Err(error) => return Err(error.into()),
+                    };
+
+                    if hash.is_none() {
+                        break;
+                    }
+
+                    if !self.options.paranoid && index.recorded(&path).await? == hash.copied() {
+                        change = Some(Change::Skip(size));
+                        break;
+                    }
 
+                    change.get_or_insert(Change::Hash(size));
+                }
+
+                let change = match change {
+                    Some(change) => change,
+                    None => Change::Download(downloader.size((*url).clone()).await.unwrap_or_else(
+                        |error| {
+                            warn!(url = url.as_str(), %error, "failed to request size");
+                            None
+                        },
+                    )),
+                };
+
+                Ok::<_, BuildError>((PathBuf::from(archive), change))
+            })
+            .buffer_unordered(jobs.get())
+            .try_collect()
+            .await?;
+
+        for (archive, change) in changes {
+            match change {
+                Change::Download(size) => {
+                    plan.download.insert(archive, size);
+                }
+                Change::Hash(size) => {
+                    plan.hash.insert(archive, size);
+                }
+                Change::Skip(size) => {
+                    plan.skip.insert(archive, size);
+                }
+            }
+        }
+
+        for (path, bytes) in files {
+            plan.write.insert(path, bytes.len() as u64);
+        }
+
+        if self.options.archive_checksums {
+            for (archive, (_, hash)) in &archives {
+                // Checksum files have a fixed size so a placeholder is used if the checksum isn't
+                // known until the archive is downloaded.
+                let (path, bytes) =
+                    Self::checksum_file(Path::new(archive), hash.unwrap_or(&Sha256([0; 32])));
+                plan.write.insert(path, bytes.len() as u64);
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Stores an archive at the relative `storage` path and returns its checksum.
+    ///
+    /// The archives are the archives that share the storage path. The download is skipped if the
+    /// archive is already stored. Archives that were cached before the blob store was enabled are
+    /// added to it rather than downloaded again.
+    async fn store(
+        &self,
+        index: &Index,
+        downloader: &Downloader,
+        storage: &Path,
+        archives: &[(&str, &Url, Option<&Sha256>)],
+    ) -> Result<Sha256, BuildError> {
+        let (relative, archive, hash) = *archives.first().expect("missing archive");
+        let source = self.path.join(storage);
+        fs::create_dir_all(&source.parent().expect("file has no parent")).await?;
+
+        // If the file already exists then the download can be skipped.
+        if self.is_cached(index, storage, hash).await? {
+            info!("skipped download");
+            return Ok(*hash.expect("cached archive has no checksum"));
+        }
+
+        for (relative, _, _) in archives {
+            let relative = Path::new(relative);
+            if relative != storage && self.is_cached(index, relative, hash).await? {
+                let hash = *hash.expect("cached archive has no checksum");
+
+                // The archive is linked (or copied) into the blob store rather than moved so that
+                // it's never missing from the `dist` tree.
+                let temporary = source.temporary();
+                temporary.remove_file_if_exists().await?;
+
+                if fs::hard_link(self.path.join(relative), &temporary)
+                    .await
+                    .is_err()
+                {
+                    fs::copy(self.path.join(relative), &temporary).await?;
+                }
+
+                source.replace_with(&temporary).await?;
+                index.insert(storage, hash).await?;
+                info!("added archive to blob store");
+                return Ok(hash);
+            }
+        }
+
+        // The archive is downloaded to a staging area and is only moved into place once the
+        // checksum is verified. Corrupt downloads are discarded so that they aren't resumed.
+        let staging = self.path.join(STAGING_DIRECTORY).join(relative);
+        fs::create_dir_all(&staging.parent().expect("file has no parent")).await?;
+        let actual = downloader.download(archive.clone(), &staging).await?;
+        if let Some(hash) = hash {
+            if actual != *hash {
+                fs::remove_file(&staging).await?;
+                return Err(BuildError::BadChecksum(archive.clone()));
+            }
+        }
+
+        source.replace_with(&staging).await?;
+        index.insert(storage, actual).await?;
+        info!("downloaded");
+        Ok(actual)
+    }
+
+    /// Builds a cache from the channel manifests at the given URLs.
+    pub async fn build(
+        &self,
+        channels: &AHashMap<Channel, Url>,
+        verification: &Verification,
+        selection: &Selection,
+        downloader: &Downloader,
+        jobs: NonZeroUsize,
+    ) -> Result<(), BuildError> {
+        let channels =
+            Self::select_manifests(channels, verification, selection, downloader, jobs).await?;
+
+        // Verify that there are no overlapping files with different checksums.
+        let archives = Self::archives(&channels)?;
         info!("found {} artefacts", archives.len());
 
-        if self.path.async_try_exists().await? {
-            let preserve = archives
-                .keys()
-                .map(|archive| self.path.join(archive))
+        let files = self.manifest_files(&channels).await?;
+
+        if !self.options.additive && self.path.async_try_exists().await? {
+            let preserve = self
+                .preserved(&archives, &files)
+                .into_iter()
+                .map(|path| self.path.join(path))
                 .collect();
 
             self.prune(preserve).await?;
             info!("pruned cache");
         }
 
-        stream::iter(channels.iter())
-            .flat_map(|(channel, manifest)| {
-                // TODO: We might download duplicate files more than once?
-                stream::iter(manifest.archives()).map(move |(archive, hash)| {
-                    async move {
-                        let destination = self.path.join(Self::relative_archive_path(
-                            channel,
-                            manifest,
-                            archive.file_name().expect("unnamed archive"),
-                        ));
-
-                        // If the file already exists then the download can be skipped.
-                        if let Some(hash) = hash {
-                            match Sha256::from_file(&destination).await {
-                                Ok(actual) => {
-                                    if *hash == actual {
-                                        info!(
-                                            file = archive.file_name().expect("unnamed archive"),
-                                            "skipped download"
-                                        );
-                                        return Ok(());
-                                    }
-                                }
-                                Err(error) => {
-                                    use std::io::ErrorKind::NotFound;
-
-                                    // Continue executing if not found.
-                                    if error.kind() != NotFound {
-                                        return Err(error.into());
-                                    }
-                                }
-                            }
-                        }
+        // Archives that share a blob are grouped so that the blob is only stored once.
+        let mut blobs = AHashMap::<_, Vec<_>>::new();
+        for (relative, (archive, hash)) in &archives {
+            blobs
+                .entry(self.storage_path(relative, *hash))
+                .or_default()
+                .push((relative.as_str(), *archive, *hash));
+        }
 
+        let index = &self.read_index().await?;
+        let result = stream::iter(blobs)
+            .map(|(storage, mut archives)| {
+                let span = info_span!("download", file = storage.to_string_lossy().as_ref());
+                async move {
+                    // The archives are sorted so that the same partial download is resumed.
+                    archives.sort_unstable_by_key(|(relative, _, _)| *relative);
+                    let actual = self.store(index, downloader, &storage, &archives).await?;
+                    for (relative, _, _) in archives {
+                        let destination = self.path.join(relative);
                         fs::create_dir_all(&destination.parent().expect("file has no parent"))
                             .await?;
-                        let bytes = downloader.download(archive.clone()).await?;
-                        if let Some(hash) = hash {
-                            if Sha256::from_slice(&bytes) != *hash {
-                                return Err(BuildError::BadChecksum(archive.clone()));
+
+                        if let Some(link) = self.options.blobs {
+                            if storage != Path::new(relative) {
+                                self.link_blob(Path::new(relative), &storage, link).await?;
                             }
                         }
 
-                        fs::write(destination, &bytes).await?;
-                        info!(
-                            file = archive.file_name().expect("unnamed archive"),
-                            "downloaded",
-                        );
-
-                        Ok(())
+                        if self.options.archive_checksums {
+                            Self::write_checksum(&destination, &actual).await?;
+                        }
                     }
-                    .instrument(info_span!(
-                        "download",
-                        channel = channel.to_string().as_str()
-                    ))
-                })
+
+                    Ok::<_, BuildError>(())
+                }
+                .instrument(span)
             })
             .map(Ok)
             .try_buffer_unordered(jobs.get())
             .try_collect::<()>()
-            .await?;
+            .await;
 
-        let normalised: AHashMap<Channel, Manifest> = channels
-            .iter()
-            .map(|(channel, manifest)| {
-                (
-                    channel.clone(),
-                    Self::normalise_manifest(channel, manifest, &self.host),
-                )
-            })
-            .collect();
+        // The index is written even if a download fails so that progress isn't lost.
+        self.write_index(index).await?;
+        result?;
 
-        // Install normalised channel manifests.
-        stream::iter(normalised.clone())
-            .map(|(channel, manifest)| async move {
-                let destination = self
-                    .path
-                    .join(Self::relative_manifest_path(&channel, &manifest));
+        // Install normalised channel manifests and aliases.
+        stream::iter(files)
+            .map(|(path, bytes)| async move {
+                let destination = self.path.join(path);
 
                 fs::create_dir_all(destination.parent().expect("file has no parent")).await?;
-                fs::write(destination, manifest.to_vec()).await?;
+                destination.write_atomic(&bytes).await?;
 
                 Ok::<_, BuildError>(())
             })
@@ -361,33 +1224,663 @@ impl Cache {
             .try_collect::<()>()
             .await?;
 
-        // Install normalised channel aliases.
-        stream::iter(
-            normalised
-                .iter()
-                .group_by(|(channel, _)| channel.name())
+        Ok(())
+    }
+
+    /// Returns the paths of every file in the cache relative to its root.
+    async fn files(&self) -> Result<Vec<PathBuf>, io::Error> {
+        let root = self.path.clone();
+        task::spawn_blocking(move || {
+            WalkDir::new(&root)
                 .into_iter()
-                .map(|(_, group)| {
-                    group
-                        .max_by_key(|(channel, _)| *channel)
-                        .expect("missing associated channel")
-                }),
+                .filter_map(|entry| match entry {
+                    Ok(entry) if entry.file_type().is_dir() => None,
+                    Ok(entry) => Some(Ok(entry
+                        .path()
+                        .strip_prefix(&root)
+                        .expect("file is outside of the cache")
+                        .to_path_buf())),
+                    Err(error) => Some(Err(error.into())),
+                })
+                .collect()
+        })
+        .await
+        .expect("panicked while listing cache")
+    }
+
+    /// Returns whether or not the checksum file that accompanies the file at `path` describes
+    /// `hash` or `None` if there is no checksum file.
+    async fn verify_checksum_file(path: &Path, hash: &Sha256) -> Result<Option<bool>, io::Error> {
+        match fs::read(path.with_suffix(".sha256")).await {
+            Ok(bytes) => Ok(Some(parse_checksum(&bytes).as_ref() == Some(hash))),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Returns the files referenced by the manifests in `files`.
+    async fn references(&self, files: &[PathBuf]) -> Result<References, io::Error> {
+        let mut references = References::default();
+        for path in files.iter().filter(|path| is_manifest(path)) {
+            references.files.extend([
+                path.clone(),
+                path.with_suffix(".sha256"),
+                path.with_suffix(".asc"),
+            ]);
+
+            match Manifest::from_slice(&fs::read(self.path.join(path)).await?) {
+                Ok(manifest) => {
+                    for (url, hash) in manifest.archives() {
+                        let archive = PathBuf::from(format!(
+                            "dist/{}/{}",
+                            manifest.date.format("%Y-%m-%d"),
+                            url.file_name().expect("unnamed archive")
+                        ));
+
+                        // Blobs are referenced by the archives with their checksum.
+                        let blob =
+                            hash.map(|hash| Path::new(BLOB_DIRECTORY).join(hash.to_string()));
+                        references
+                            .files
+                            .extend([archive.with_suffix(".sha256"), archive.clone()]);
+                        references.files.extend(blob);
+                        references.archives.insert(archive, hash.copied());
+                    }
+                }
+                Err(_) => references.corrupt.push(path.clone()),
+            }
+        }
+
+        Ok(references)
+    }
+
+    /// Deletes files that aren't referenced by the manifests in the cache. Empty directories are
+    /// removed.
+    ///
+    /// Partial downloads of referenced archives are preserved so that they can be resumed. Nothing
+    /// is deleted if a manifest can't be parsed because the files that it references are unknown.
+    pub async fn prune_unreferenced(&self) -> Result<(), io::Error> {
+        let files = self.files().await?;
+        let references = self.references(&files).await?;
+        if let Some(path) = references.corrupt.first() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad manifest '{}'", path.display()),
+            ));
+        }
+
+        let preserve = references
+            .archives
+            .keys()
+            .map(|archive| Path::new(STAGING_DIRECTORY).join(archive))
+            .chain(references.files)
+            .chain([PathBuf::from(INDEX_FILE), PathBuf::from(LOCK_FILE)])
+            .map(|path| self.path.join(path))
+            .collect();
+
+        self.prune(preserve).await
+    }
+
+    /// Verifies the contents of the cache without downloading anything.
+    ///
+    /// Every archive referenced by a cached manifest is hashed and compared with the checksum in
+    /// the manifest. Checksum files must describe the files that they accompany and every manifest
+    /// must be accompanied by one. Files that aren't referenced by a manifest are reported unless
+    /// they belong to the tool.
+    pub async fn verify(&self, jobs: NonZeroUsize) -> Result<Report, io::Error> {
+        // Files in the state directory belong to the tool.
+        let files = self
+            .files()
+            .await?
+            .into_iter()
+            .filter(|path| !path.starts_with(STATE_DIRECTORY))
+            .collect::<Vec<_>>();
+
+        let references = self.references(&files).await?;
+        let mut report = Report {
+            corrupt: references.corrupt,
+            empty: !files.iter().any(|path| is_manifest(path)),
+            ..Report::default()
+        };
+
+        for path in files.iter().filter(|path| is_manifest(path)) {
+            let checksum = path.with_suffix(".sha256");
+            let bytes = fs::read(self.path.join(path)).await?;
+            match Self::verify_checksum_file(&self.path.join(path), &Sha256::from_slice(&bytes))
+                .await?
+            {
+                Some(true) => {}
+                Some(false) => report.corrupt.push(checksum),
+                None => report.missing.push(checksum),
+            }
+        }
+
+        let reports: Vec<Report> = stream::iter(references.archives)
+            .map(|(archive, hash)| async move {
+                let mut report = Report::default();
+                let actual = match Sha256::from_file(&self.path.join(&archive)).await {
+                    Ok(actual) => actual,
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                        report.missing.push(archive);
+                        return Ok(report);
+                    }
+                    Err(error) => return Err(error),
+                };
+
+                if hash.is_some_and(|hash| hash != actual) {
+                    report.corrupt.push(archive.clone());
+                }
+
+                if Self::verify_checksum_file(&self.path.join(&archive), &actual).await?
+                    == Some(false)
+                {
+                    report.corrupt.push(archive.with_suffix(".sha256"));
+                }
+
+                info!(file = archive.to_string_lossy().as_ref(), "verified");
+                Ok::<_, io::Error>(report)
+            })
+            .buffer_unordered(jobs.get())
+            .try_collect()
+            .await?;
+
+        for other in reports {
+            report.extend(other);
+        }
+
+        let protected = self.protected().await?;
+        report.unreferenced = files
+            .into_iter()
+            .filter(|path| !references.files.contains(path) && !protected.matches(path, false))
+            .collect();
+
+        report.missing.sort();
+        report.corrupt.sort();
+        report.unreferenced.sort();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::{FileDownloader, Retry};
+    use toml::Value;
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/channel-rust-1.60.0.toml");
+
+    /// Returns the packages that `artefact` refers to.
+    fn references(artefact: &Artefact) -> (Vec<&str>, Vec<&str>) {
+        (
+            artefact
+                .components
+                .iter()
+                .map(|component| component.pkg.as_str())
+                .collect(),
+            artefact
+                .extensions
+                .iter()
+                .map(|component| component.pkg.as_str())
+                .collect(),
         )
-        .map(|(channel, manifest)| async {
-            let destination = self
-                .path
-                .join(format!("dist/channel-rust-{}.toml", channel.name()));
+    }
 
-            fs::create_dir_all(destination.parent().expect("file has no parent")).await?;
-            fs::write(destination, manifest.to_vec()).await?;
+    #[test]
+    fn select_keeps_toolchain_package() {
+        let manifest = Manifest::from_slice(FIXTURE).expect("invalid manifest");
+        let selection = Selection {
+            targets: Some(
+                ["x86_64-unknown-linux-gnu".to_string()]
+                    .into_iter()
+                    .collect(),
+            ),
+            profile: Some("minimal".to_string()),
+            ..Selection::default()
+        };
 
-            Ok::<_, BuildError>(())
-        })
-        .map(Ok)
-        .try_buffer_unordered(jobs.get())
-        .try_collect::<()>()
-        .await?;
+        let selected = Cache::select_manifest(&manifest, &selection).expect("invalid selection");
+        let toolchain = &selected.packages[TOOLCHAIN_PACKAGE].artefacts;
+        assert!(toolchain["x86_64-unknown-linux-gnu"].available);
+        assert!(!toolchain["x86_64-unknown-linux-gnu"].archives.is_empty());
+        assert!(!toolchain["aarch64-unknown-linux-gnu"].available);
+        assert_eq!(
+            references(&toolchain["x86_64-unknown-linux-gnu"]),
+            (vec!["rustc", "rust-std", "cargo"], vec!["rust-std"])
+        );
 
-        Ok(())
+        assert!(selected.packages["rustc"].artefacts["x86_64-unknown-linux-gnu"].available);
+        assert!(!selected.packages.contains_key("rust-src"));
+        assert!(!selected.packages.contains_key("clippy-preview"));
+    }
+
+    #[test]
+    fn select_components_and_extensions() {
+        let manifest = Manifest::from_slice(FIXTURE).expect("invalid manifest");
+        let selection = Selection {
+            packages: Some([TOOLCHAIN_PACKAGE.to_string()].into_iter().collect()),
+            ..Selection::default()
+        };
+
+        let selected = Cache::select_manifest(&manifest, &selection).expect("invalid selection");
+        assert_eq!(selected.packages.len(), manifest.packages.len());
+        assert_eq!(
+            references(&selected.packages[TOOLCHAIN_PACKAGE].artefacts["x86_64-unknown-linux-gnu"]),
+            (
+                vec!["rustc", "rust-std", "cargo"],
+                vec!["clippy-preview", "rust-std", "rust-src"]
+            )
+        );
+    }
+
+    #[test]
+    fn select_renamed_packages() {
+        let manifest = Manifest::from_slice(FIXTURE).expect("invalid manifest");
+        let mut selection = Selection {
+            packages: Some(["clippy".to_string()].into_iter().collect()),
+            ..Selection::default()
+        };
+
+        let selected = Cache::select_manifest(&manifest, &selection).expect("invalid selection");
+        assert!(selected.packages.contains_key("clippy-preview"));
+
+        selection.packages = Some(["rust-analyzer".to_string()].into_iter().collect());
+        assert!(matches!(
+            Cache::select_manifest(&manifest, &selection),
+            Err(BuildError::UnknownPackage(package)) if package == "rust-analyzer"
+        ));
+    }
+
+    #[test]
+    fn select_newer_compression_formats() {
+        let manifest = Manifest::from_slice(
+            br#"
+                date = "2022-04-07"
+
+                [pkg.rust.target.x86_64-unknown-linux-gnu]
+                available = true
+                xz_url = "https://example.org/dist/2022-04-07/rust.tar.xz"
+                zst_url = "https://example.org/dist/2022-04-07/rust.tar.zst"
+                zst_hash = "23480d9181986c44a7208e7da49706605efef8d64f66a25999dbc02c0b444b47"
+            "#,
+        )
+        .expect("invalid manifest");
+
+        let selection = Selection {
+            compressions: Some(
+                [Compression::from_str("zst").expect("invalid format")]
+                    .into_iter()
+                    .collect(),
+            ),
+            ..Selection::default()
+        };
+
+        let selected = Cache::select_manifest(&manifest, &selection).expect("invalid selection");
+        let channel = Channel::from_str("stable:1.60.0").expect("invalid channel");
+        let host = Url::parse("https://mirror.example.org/").expect("invalid url");
+        let normalised = Cache::normalise_manifest(&channel, &selected, &host);
+        assert_eq!(
+            normalised
+                .archives()
+                .map(|(url, _)| url.as_str())
+                .collect::<Vec<_>>(),
+            ["https://mirror.example.org/dist/2022-04-07/rust.tar.zst"]
+        );
+    }
+
+    #[test]
+    fn normalise_preserves_unknown_urls() {
+        let manifest = Manifest::from_slice(
+            br#"
+                manifest-version = "2"
+                date = "2022-04-07"
+                homepage_url = "https://example.org/index.html"
+
+                [pkg.rust.target.x86_64-unknown-linux-gnu]
+                available = true
+                url = "https://example.org/dist/2022-04-07/rust.tar.gz"
+
+                [artifacts.source-code]
+                url = "https://example.org/dist/2022-04-07/rustc-src.tar.gz"
+            "#,
+        )
+        .expect("invalid manifest");
+
+        let channel = Channel::from_str("stable:1.60.0").expect("invalid channel");
+        let host = Url::parse("https://mirror.example.org/rust/").expect("invalid url");
+        let manifest = Cache::normalise_manifest(&channel, &manifest, &host);
+        let value = toml::from_slice::<Value>(&manifest.to_vec()).expect("invalid toml");
+
+        assert_eq!(
+            value["artifacts"]["source-code"]["url"].as_str(),
+            Some("https://example.org/dist/2022-04-07/rustc-src.tar.gz")
+        );
+        assert_eq!(
+            value["pkg"]["rust"]["target"]["x86_64-unknown-linux-gnu"]["url"].as_str(),
+            Some("https://mirror.example.org/rust/dist/2022-04-07/rust.tar.gz")
+        );
+        assert_eq!(
+            value["homepage_url"].as_str(),
+            Some("https://example.org/index.html")
+        );
+    }
+
+    /// Returns a downloader that copies files and never retries.
+    fn downloader() -> Downloader {
+        Downloader::new(
+            FileDownloader::default(),
+            Retry {
+                attempts: NonZeroUsize::new(1).expect("zero attempts"),
+                ..Retry::default()
+            },
+        )
+    }
+
+    /// Fetches the fixture manifest from `directory` and verifies it.
+    async fn fetch_manifest(
+        directory: &Path,
+        verification: &Verification,
+    ) -> Result<(), BuildError> {
+        let channel = Channel::from_str("stable:1.60.0").expect("invalid channel");
+        let url =
+            Url::from_file_path(directory.join("channel-rust-1.60.0.toml")).expect("invalid path");
+        Cache::fetch_manifests(
+            &[(channel, url)].into_iter().collect(),
+            verification,
+            &downloader(),
+            NonZeroUsize::new(1).expect("zero jobs"),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Fetches the fixture manifest from `directory` and verifies its signature.
+    async fn fetch_signed_manifest(directory: &Path) -> Result<(), BuildError> {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let verification = Verification {
+            checksums: false,
+            keyring: Some(
+                Keyring::from_file(&fixtures.join("test-key.pub.asc"))
+                    .expect("failed to read keyring"),
+            ),
+        };
+
+        fetch_manifest(directory, &verification).await
+    }
+
+    #[tokio::test]
+    async fn manifest_checksum() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let directory = tempfile::tempdir().expect("failed to create directory");
+        let manifest = directory.path().join("channel-rust-1.60.0.toml");
+        fs::copy(fixtures.join("channel-rust-1.60.0.toml"), &manifest)
+            .await
+            .expect("failed to copy manifest");
+
+        let verification = Verification {
+            checksums: true,
+            keyring: None,
+        };
+        let url = Url::from_file_path(&manifest).expect("invalid path");
+        assert!(matches!(
+            fetch_manifest(directory.path(), &verification).await,
+            Err(BuildError::MissingManifestChecksum(missing)) if missing == url
+        ));
+
+        Cache::write_checksum(&manifest, &Sha256::from_slice(FIXTURE))
+            .await
+            .expect("failed to write checksum");
+        fetch_manifest(directory.path(), &verification)
+            .await
+            .expect("checksum should match");
+
+        Cache::write_checksum(&manifest, &Sha256([0; 32]))
+            .await
+            .expect("failed to write checksum");
+        assert!(matches!(
+            fetch_manifest(directory.path(), &verification).await,
+            Err(BuildError::BadManifestChecksum(bad)) if bad == url
+        ));
+    }
+
+    #[tokio::test]
+    async fn signed_manifest() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        fetch_signed_manifest(&fixtures)
+            .await
+            .expect("signature should be trusted");
+    }
+
+    #[tokio::test]
+    async fn missing_manifest_signature() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let directory = tempfile::tempdir().expect("failed to create directory");
+        fs::copy(
+            fixtures.join("channel-rust-1.60.0.toml"),
+            directory.path().join("channel-rust-1.60.0.toml"),
+        )
+        .await
+        .expect("failed to copy manifest");
+
+        let url = Url::from_file_path(directory.path().join("channel-rust-1.60.0.toml"))
+            .expect("invalid path");
+        assert!(matches!(
+            fetch_signed_manifest(directory.path()).await,
+            Err(BuildError::MissingManifestSignature(missing)) if missing == url
+        ));
+    }
+
+    #[tokio::test]
+    async fn untrusted_manifest_signature() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let directory = tempfile::tempdir().expect("failed to create directory");
+        for (source, destination) in [
+            ("channel-rust-1.60.0.toml", "channel-rust-1.60.0.toml"),
+            (
+                "channel-rust-1.60.0.toml.untrusted.asc",
+                "channel-rust-1.60.0.toml.asc",
+            ),
+        ] {
+            fs::copy(fixtures.join(source), directory.path().join(destination))
+                .await
+                .expect("failed to copy fixture");
+        }
+
+        assert!(matches!(
+            fetch_signed_manifest(directory.path()).await,
+            Err(BuildError::BadManifestSignature(
+                _,
+                signature::Error::UnknownSigner
+            ))
+        ));
+    }
+
+    /// Publishes a manifest for the stable `version` in `upstream` with a single archive that
+    /// contains `contents` and returns the URL of the manifest.
+    async fn publish(upstream: &Path, version: &str, date: &str, contents: &[u8]) -> Url {
+        let archive = upstream.join(date).join("rust.tar.xz");
+        fs::create_dir_all(archive.parent().expect("file has no parent"))
+            .await
+            .expect("failed to create directory");
+        fs::write(&archive, contents)
+            .await
+            .expect("failed to write archive");
+
+        let manifest = upstream.join(format!("channel-rust-{version}.toml"));
+        let url = Url::from_file_path(&archive).expect("invalid path");
+        let hash = Sha256::from_slice(contents);
+        fs::write(
+            &manifest,
+            format!(
+                "date = \"{date}\"\n\
+                 [pkg.rust.target.x86_64-unknown-linux-gnu]\n\
+                 available = true\n\
+                 xz_url = \"{url}\"\n\
+                 xz_hash = \"{hash}\"\n"
+            ),
+        )
+        .await
+        .expect("failed to write manifest");
+
+        Url::from_file_path(manifest).expect("invalid path")
+    }
+
+    /// Builds a cache at `root` from the manifests of the stable `channels`.
+    async fn build(root: &Path, channels: &[(&str, &Url)], options: Options) {
+        let host = Url::parse("https://mirror.example.org/").expect("invalid url");
+        let channels = channels
+            .iter()
+            .map(|(version, url)| {
+                let channel =
+                    Channel::from_str(&format!("stable:{version}")).expect("invalid channel");
+                (channel, (*url).clone())
+            })
+            .collect();
+
+        Cache::new(root.to_path_buf(), host, options)
+            .build(
+                &channels,
+                &Verification::default(),
+                &Selection::default(),
+                &downloader(),
+                NonZeroUsize::new(1).expect("zero jobs"),
+            )
+            .await
+            .expect("failed to build cache");
+    }
+
+    /// Returns the relative path of the blob with `contents`.
+    fn blob(contents: &[u8]) -> PathBuf {
+        Path::new(BLOB_DIRECTORY).join(Sha256::from_slice(contents).to_string())
+    }
+
+    /// Returns the relative paths of the blobs in the cache at `root`.
+    fn blobs(root: &Path) -> Vec<PathBuf> {
+        WalkDir::new(root.join(BLOB_DIRECTORY))
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .map(|entry| {
+                entry
+                    .expect("failed to list blobs")
+                    .path()
+                    .strip_prefix(root)
+                    .expect("blob outside of cache")
+                    .to_path_buf()
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shared_archives_are_stored_once() {
+        use std::os::unix::fs::MetadataExt;
+
+        let upstream = tempfile::tempdir().expect("failed to create directory");
+        let root = tempfile::tempdir().expect("failed to create directory");
+        let old = publish(upstream.path(), "1.60.0", "2022-04-07", b"rust").await;
+        let new = publish(upstream.path(), "1.61.0", "2022-05-19", b"rust").await;
+
+        let options = Options {
+            blobs: Some(Link::Hard),
+            ..Options::default()
+        };
+        build(root.path(), &[("1.60.0", &old), ("1.61.0", &new)], options).await;
+
+        assert_eq!(blobs(root.path()), [blob(b"rust")]);
+        let blob = fs::metadata(root.path().join(blob(b"rust")))
+            .await
+            .expect("missing blob");
+        for archive in ["dist/2022-04-07/rust.tar.xz", "dist/2022-05-19/rust.tar.xz"] {
+            let metadata = fs::symlink_metadata(root.path().join(archive))
+                .await
+                .expect("missing archive");
+            assert_eq!((metadata.dev(), metadata.ino()), (blob.dev(), blob.ino()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn switch_between_hard_and_symbolic_links() {
+        use std::os::unix::fs::MetadataExt;
+
+        let upstream = tempfile::tempdir().expect("failed to create directory");
+        let root = tempfile::tempdir().expect("failed to create directory");
+        let url = publish(upstream.path(), "1.60.0", "2022-04-07", b"rust").await;
+        let archive = root.path().join("dist/2022-04-07/rust.tar.xz");
+
+        for link in [Link::Hard, Link::Symbolic, Link::Hard] {
+            let options = Options {
+                blobs: Some(link),
+                ..Options::default()
+            };
+            build(root.path(), &[("1.60.0", &url)], options).await;
+
+            let metadata = fs::symlink_metadata(&archive)
+                .await
+                .expect("missing archive");
+            match link {
+                Link::Hard => {
+                    let blob = fs::metadata(root.path().join(blob(b"rust")))
+                        .await
+                        .expect("missing blob");
+                    assert_eq!((metadata.dev(), metadata.ino()), (blob.dev(), blob.ino()));
+                }
+                Link::Symbolic => {
+                    assert!(metadata.file_type().is_symlink());
+                    assert_eq!(
+                        fs::read_link(&archive).await.expect("missing link"),
+                        Path::new("../..").join(blob(b"rust"))
+                    );
+                }
+            }
+
+            assert_eq!(
+                fs::read(&archive).await.expect("failed to read archive"),
+                b"rust"
+            );
+            assert_eq!(blobs(root.path()), [blob(b"rust")]);
+        }
+    }
+
+    #[tokio::test]
+    async fn unreferenced_blobs_are_pruned() {
+        let upstream = tempfile::tempdir().expect("failed to create directory");
+        let root = tempfile::tempdir().expect("failed to create directory");
+        let old = publish(upstream.path(), "1.60.0", "2022-04-07", b"old").await;
+        let new = publish(upstream.path(), "1.61.0", "2022-05-19", b"new").await;
+
+        let options = Options {
+            blobs: Some(Link::Hard),
+            additive: true,
+            ..Options::default()
+        };
+        build(root.path(), &[("1.60.0", &old)], options.clone()).await;
+        build(root.path(), &[("1.61.0", &new)], options.clone()).await;
+        assert_eq!(blobs(root.path()), [blob(b"new"), blob(b"old")]);
+
+        // Pruning a cache without the manifest of a channel prunes the blobs that only it refers
+        // to.
+        fs::remove_file(root.path().join("dist/channel-rust-1.61.0.toml"))
+            .await
+            .expect("failed to remove manifest");
+        fs::remove_file(root.path().join("dist/channel-rust-stable.toml"))
+            .await
+            .expect("failed to remove alias");
+        let host = Url::parse("https://mirror.example.org/").expect("invalid url");
+        Cache::new(root.path().to_path_buf(), host, options.clone())
+            .prune_unreferenced()
+            .await
+            .expect("failed to prune cache");
+        assert_eq!(blobs(root.path()), [blob(b"old")]);
+        assert!(!root.path().join("dist/2022-05-19").exists());
+
+        // Building a cache without a channel prunes the blobs that only it refers to.
+        let options = Options {
+            additive: false,
+            ..options
+        };
+        build(root.path(), &[("1.61.0", &new)], options).await;
+        assert_eq!(blobs(root.path()), [blob(b"new")]);
+        assert!(!root.path().join("dist/2022-04-07").exists());
     }
 }
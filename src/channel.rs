@@ -17,7 +17,7 @@ pub enum ParseVersionError {
 impl Display for ParseVersionError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Self::InvalidComponent(s) => write!(f, "invalid component '{}'", s),
+            Self::InvalidComponent(s) => write!(f, "invalid component '{s}'"),
             Self::MissingMajor => write!(f, "missing major"),
             Self::MissingMinor => write!(f, "missing minor"),
             Self::MissingSubminor => write!(f, "missing subminor"),
@@ -125,12 +125,49 @@ impl Channel {
             Self::DateBased { name, date: _ } => name,
         }
     }
+
+    /// Returns the path of the channel manifest relative to the root of a distribution server.
+    ///
+    /// The official distribution server hosts a large number of stable manifest copies in unusual
+    /// places. These are not replicated here because it is not clear how they are used.
+    #[must_use]
+    pub fn relative_manifest_path(&self) -> String {
+        match self {
+            Self::Stable(version) => format!("dist/channel-rust-{version}.toml"),
+            Self::DateBased { name, date } => {
+                format!("dist/{}/channel-rust-{name}.toml", date.format("%Y-%m-%d"))
+            }
+        }
+    }
+
+    /// Returns the channel with the manifest at `path` relative to the root of a distribution
+    /// server or `None` if it isn't the path of a channel manifest.
+    ///
+    /// This is the inverse of [`Self::relative_manifest_path`] so aliases are not recognised.
+    #[must_use]
+    pub fn from_relative_manifest_path(path: &str) -> Option<Self> {
+        let path = path.strip_prefix("dist/")?;
+        let (directory, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let name = name.strip_prefix("channel-rust-")?.strip_suffix(".toml")?;
+
+        let channel = if directory.is_empty() {
+            Self::Stable(Version::from_str(name).ok()?)
+        } else {
+            Self::DateBased {
+                name: name.to_string(),
+                date: NaiveDate::parse_from_str(directory, "%Y-%m-%d").ok()?,
+            }
+        };
+
+        // Paths that aren't in a canonical form are rejected.
+        (channel.relative_manifest_path() == format!("dist/{path}")).then_some(channel)
+    }
 }
 
 impl Display for Channel {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Stable(version) => write!(f, "stable:{}", version),
+            Self::Stable(version) => write!(f, "stable:{version}"),
             Self::DateBased { name, date } => write!(f, "{}:{}", name, date.format("%Y-%m-%d")),
         }
     }
@@ -160,71 +197,525 @@ impl FromStr for Channel {
 
 pub mod manifest {
     use crate::digest::Sha256;
-    use ahash::AHashMap;
     use chrono::NaiveDate;
+    use indexmap::IndexMap;
+    use itertools::Itertools;
     use serde::{Deserialize, Serialize};
-    use std::collections::BTreeMap;
+    use std::{
+        error::Error,
+        fmt::{self, Display, Formatter},
+        str::FromStr,
+    };
+    use toml::{value::Table, Value};
     use url::Url;
 
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub struct ParseCompressionError(String);
+
+    impl Display for ParseCompressionError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "unknown compression format '{}'", self.0)
+        }
+    }
+
+    impl Error for ParseCompressionError {}
+
+    /// Represents the compression format of an archive.
+    ///
+    /// The archive compressed with a format is described by the `<format>_url` and `<format>_hash`
+    /// fields of an artefact except for `gz` archives, which are described by the `url` and `hash`
+    /// fields. Formats other than `gz` and `xz` are only recognised when both fields are present so
+    /// that newer formats are cached when they appear.
+    #[derive(Clone, Debug, Eq, PartialEq, Hash)]
+    pub enum Compression {
+        Gz,
+        Xz,
+        Other(String),
+    }
+
+    impl Compression {
+        /// Returns the keys of the fields that describe the URL and checksum of an archive.
+        #[must_use]
+        fn keys(&self) -> (String, String) {
+            match self {
+                Self::Gz => ("url".to_string(), "hash".to_string()),
+                format => (format!("{format}_url"), format!("{format}_hash")),
+            }
+        }
+
+        /// Returns the format of the archive with a URL described by the field with `key`.
+        fn from_url_key(key: &str) -> Option<Self> {
+            match key {
+                "url" => Some(Self::Gz),
+                _ => Self::from_str(key.strip_suffix("_url")?).ok(),
+            }
+        }
+    }
+
+    impl Display for Compression {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Gz => write!(f, "gz"),
+                Self::Xz => write!(f, "xz"),
+                Self::Other(format) => write!(f, "{format}"),
+            }
+        }
+    }
+
+    impl FromStr for Compression {
+        type Err = ParseCompressionError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "gz" => Ok(Self::Gz),
+                "xz" => Ok(Self::Xz),
+                _ if !s.is_empty()
+                    && s.chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) =>
+                {
+                    Ok(Self::Other(s.to_string()))
+                }
+                _ => Err(ParseCompressionError(s.to_string())),
+            }
+        }
+    }
+
+    /// Represents a reference to the artefact of another package.
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+    pub struct Component {
+        pub pkg: String,
+        pub target: String,
+    }
+
+    /// Represents an archive of an artefact.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Archive {
+        pub url: Url,
+        pub hash: Option<Sha256>,
+    }
+
+    /// Represents an artefact as it's described by a manifest.
+    #[derive(Deserialize, Serialize)]
+    struct RawArtefact {
+        available: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        components: Vec<Component>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        extensions: Vec<Component>,
+        #[serde(flatten)]
+        fields: Table,
+    }
+
     /// Represents an artefact.
     ///
     /// # Limitations
     ///
     /// Ideally, this type should be broken up into two with the `available` field acting as a tag but
     /// serde doesn't support non-string tags (<https://github.com/serde-rs/serde/issues/745>).
-    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    #[serde(try_from = "RawArtefact", into = "RawArtefact")]
     pub struct Artefact {
         pub available: bool,
-        pub url: Option<Url>,
-        pub hash: Option<Sha256>,
-        pub xz_url: Option<Url>,
-        pub xz_hash: Option<Sha256>,
+        /// The archives of the artefact by compression format.
+        pub archives: IndexMap<Compression, Archive>,
+        /// The components that are installed with the artefact.
+        pub components: Vec<Component>,
+        /// The components that can optionally be installed with the artefact.
+        pub extensions: Vec<Component>,
+        /// Fields that are not understood.
+        pub unknown: Table,
+    }
+
+    impl TryFrom<RawArtefact> for Artefact {
+        type Error = String;
+
+        fn try_from(raw: RawArtefact) -> Result<Self, Self::Error> {
+            let mut unknown = raw.fields;
+            let mut archives = IndexMap::new();
+            let compressions = unknown
+                .keys()
+                .filter_map(|key| Compression::from_url_key(key))
+                .collect::<Vec<_>>();
+
+            for compression in compressions {
+                let (url_key, hash_key) = compression.keys();
+                if matches!(compression, Compression::Other(_)) && !unknown.contains_key(&hash_key)
+                {
+                    continue;
+                }
+
+                let url = unknown
+                    .remove(&url_key)
+                    .and_then(|url| url.as_str().and_then(|url| Url::parse(url).ok()))
+                    .ok_or_else(|| format!("invalid url in '{url_key}'"))?;
+
+                let hash = unknown
+                    .remove(&hash_key)
+                    .map(|hash| {
+                        hash.as_str()
+                            .and_then(|hash| Sha256::from_str(hash).ok())
+                            .ok_or_else(|| format!("invalid checksum in '{hash_key}'"))
+                    })
+                    .transpose()?;
+
+                archives.insert(compression, Archive { url, hash });
+            }
+
+            Ok(Self {
+                available: raw.available,
+                archives,
+                components: raw.components,
+                extensions: raw.extensions,
+                unknown,
+            })
+        }
+    }
+
+    impl From<Artefact> for RawArtefact {
+        fn from(artefact: Artefact) -> Self {
+            let mut fields = Table::new();
+            for (compression, archive) in artefact.archives {
+                let (url_key, hash_key) = compression.keys();
+                fields.insert(url_key, Value::String(archive.url.to_string()));
+                if let Some(hash) = archive.hash {
+                    fields.insert(hash_key, Value::String(hash.to_string()));
+                }
+            }
+
+            fields.extend(artefact.unknown);
+            Self {
+                available: artefact.available,
+                components: artefact.components,
+                extensions: artefact.extensions,
+                fields,
+            }
+        }
     }
 
     /// Represents data belonging to a package.
-    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
     pub struct PackageData {
         pub version: Option<String>,
         pub git_commit_hash: Option<String>,
         #[serde(rename = "target")]
-        pub artefacts: BTreeMap<String, Artefact>,
+        pub artefacts: IndexMap<String, Artefact>,
+        /// Fields that are not understood.
+        #[serde(flatten)]
+        pub unknown: Table,
+    }
+
+    /// Represents a package that has been renamed.
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+    pub struct Rename {
+        pub to: String,
     }
 
     /// Represents a channel manifest.
-    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
     pub struct Manifest {
+        #[serde(rename = "manifest-version")]
+        pub version: Option<String>,
         pub date: NaiveDate,
         #[serde(rename = "pkg")]
-        pub packages: AHashMap<String, PackageData>,
+        pub packages: IndexMap<String, PackageData>,
+        #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+        pub renames: IndexMap<String, Rename>,
+        #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+        pub profiles: IndexMap<String, Vec<String>>,
+        /// Fields that are not understood.
+        #[serde(flatten)]
+        pub unknown: Table,
+        /// The document that the manifest was parsed from. Fields are serialised in the same order
+        /// as they appear in the document.
+        #[serde(skip)]
+        pub layout: Option<Value>,
+    }
+
+    /// Orders the keys of every table in `value` by their position in the corresponding table in
+    /// `layout`. Keys that aren't in `layout` follow the keys that are.
+    fn reorder(value: &mut Value, layout: &Value) {
+        match (value, layout) {
+            (Value::Table(table), Value::Table(layout)) => {
+                let mut entries = std::mem::take(table).into_iter().collect::<Vec<_>>();
+                entries.sort_by_key(|(key, _)| {
+                    layout
+                        .keys()
+                        .position(|other| other == key)
+                        .unwrap_or(usize::MAX)
+                });
+
+                for (key, mut value) in entries {
+                    if let Some(layout) = layout.get(&key) {
+                        reorder(&mut value, layout);
+                    }
+
+                    table.insert(key, value);
+                }
+            }
+            (Value::Array(array), Value::Array(layout)) => {
+                for (value, layout) in array.iter_mut().zip(layout) {
+                    reorder(value, layout);
+                }
+            }
+            _ => {}
+        }
     }
 
     impl Manifest {
         /// Returns an iterator of files tracked by the manifest and their checksum.
         pub fn archives(&self) -> impl Iterator<Item = (&Url, Option<&Sha256>)> {
-            self.packages.iter().flat_map(|(_, data)| {
-                data.artefacts.iter().flat_map(|(_, artefact)| {
+            self.packages.values().flat_map(|data| {
+                data.artefacts.values().flat_map(|artefact| {
                     artefact
-                        .url
-                        .iter()
-                        .map(|url| (url, artefact.hash.as_ref()))
-                        .chain(
-                            artefact
-                                .xz_url
-                                .iter()
-                                .map(|url| (url, artefact.xz_hash.as_ref())),
-                        )
+                        .archives
+                        .values()
+                        .map(|archive| (&archive.url, archive.hash.as_ref()))
                 })
             })
         }
 
+        /// Returns the tables of fields that are not understood along with the dotted path of the
+        /// table that they belong to.
+        fn unknown_tables(&self) -> Vec<(String, &Table)> {
+            let mut tables = vec![(String::new(), &self.unknown)];
+            for (package, data) in self.packages.iter().sorted_by_key(|(package, _)| *package) {
+                tables.push((format!("pkg.{package}."), &data.unknown));
+                for (target, artefact) in &data.artefacts {
+                    tables.push((format!("pkg.{package}.target.{target}."), &artefact.unknown));
+                }
+            }
+
+            tables
+        }
+
+        /// Returns the keys of fields that are not understood.
+        ///
+        /// Keys are dotted paths relative to the root of the manifest.
+        pub fn unknown_keys(&self) -> Vec<String> {
+            self.unknown_tables()
+                .into_iter()
+                .flat_map(|(prefix, table)| table.keys().map(move |key| format!("{prefix}{key}")))
+                .collect()
+        }
+
+        /// Returns the URLs in fields that are not understood along with their keys.
+        ///
+        /// URLs are recognised by keys that end with `url` and are searched for recursively. They
+        /// aren't cached so they're left pointing to wherever they pointed.
+        pub fn unknown_urls(&self) -> Vec<(String, &str)> {
+            fn search<'a>(
+                path: String,
+                key: &str,
+                value: &'a Value,
+                urls: &mut Vec<(String, &'a str)>,
+            ) {
+                match value {
+                    Value::String(url) if key.ends_with("url") => urls.push((path, url)),
+                    Value::Table(table) => {
+                        for (key, value) in table {
+                            search(format!("{path}.{key}"), key, value, urls);
+                        }
+                    }
+                    Value::Array(array) => {
+                        for (index, value) in array.iter().enumerate() {
+                            search(format!("{path}[{index}]"), key, value, urls);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            let mut urls = Vec::new();
+            for (prefix, table) in self.unknown_tables() {
+                for (key, value) in table {
+                    search(format!("{prefix}{key}"), key, value, &mut urls);
+                }
+            }
+
+            urls
+        }
+
         /// Deserialises a manifest from a slice.
         pub fn from_slice(slice: &[u8]) -> Result<Self, toml::de::Error> {
-            toml::from_slice(slice)
+            let layout = toml::from_slice::<Value>(slice)?;
+            let mut manifest = layout.clone().try_into::<Self>()?;
+            manifest.layout = Some(layout);
+            Ok(manifest)
         }
 
         /// Serialises a manifest into a vector of bytes.
+        ///
+        /// Fields are serialised in the order that they appear in the document that the manifest was
+        /// parsed from. TOML requires the plain values of a table to precede its subtables so the
+        /// manifest is first converted to a value, which emits them in that order.
         pub fn to_vec(&self) -> Vec<u8> {
-            toml::to_vec(self).expect("failed to serialise manifest")
+            let mut value = Value::try_from(self).expect("failed to convert manifest");
+            if let Some(layout) = &self.layout {
+                reorder(&mut value, layout);
+            }
+
+            toml::to_vec(&value).expect("failed to serialise manifest")
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/channel-rust-1.60.0.toml");
+
+        /// Returns the dotted path of every key in `value` in the order that they appear.
+        fn keys(value: &Value, prefix: &str) -> Vec<String> {
+            match value {
+                Value::Table(table) => table
+                    .iter()
+                    .flat_map(|(key, value)| {
+                        let path = format!("{prefix}.{key}");
+                        std::iter::once(path.clone()).chain(keys(value, &path))
+                    })
+                    .collect(),
+                Value::Array(array) => array
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(index, value)| keys(value, &format!("{prefix}[{index}]")))
+                    .collect(),
+                _ => Vec::new(),
+            }
+        }
+
+        fn parse(bytes: &[u8]) -> Value {
+            toml::from_slice(bytes).expect("invalid toml")
+        }
+
+        #[test]
+        fn round_trip_preserves_fields_and_order() {
+            let manifest = Manifest::from_slice(FIXTURE).expect("invalid manifest");
+            let bytes = manifest.to_vec();
+
+            assert_eq!(parse(&bytes), parse(FIXTURE));
+            assert_eq!(keys(&parse(&bytes), ""), keys(&parse(FIXTURE), ""));
+        }
+
+        #[test]
+        fn serialisation_is_deterministic() {
+            let bytes = Manifest::from_slice(FIXTURE)
+                .expect("invalid manifest")
+                .to_vec();
+
+            for _ in 0..4 {
+                let manifest = Manifest::from_slice(FIXTURE).expect("invalid manifest");
+                assert_eq!(manifest.to_vec(), bytes);
+                assert_eq!(
+                    Manifest::from_slice(&bytes)
+                        .expect("invalid manifest")
+                        .to_vec(),
+                    bytes
+                );
+            }
+        }
+
+        #[test]
+        fn newer_compression_formats_are_archives() {
+            let document = br#"
+                date = "2022-04-07"
+
+                [pkg.rust.target.x86_64-unknown-linux-gnu]
+                available = true
+                url = "https://example.org/dist/2022-04-07/rust.tar.gz"
+                hash = "69e3a84b6e3fa6200d16da08a79f710b40ad6f76318b1d8a08bce6df4a9d514c"
+                zst_url = "https://example.org/dist/2022-04-07/rust.tar.zst"
+                zst_hash = "23480d9181986c44a7208e7da49706605efef8d64f66a25999dbc02c0b444b47"
+                release_notes_url = "https://example.org/notes.html"
+            "#;
+
+            let manifest = Manifest::from_slice(document).expect("invalid manifest");
+            let artefact = &manifest.packages["rust"].artefacts["x86_64-unknown-linux-gnu"];
+            assert_eq!(
+                artefact.archives.keys().cloned().collect::<Vec<_>>(),
+                [Compression::Gz, Compression::Other("zst".to_string())]
+            );
+            assert_eq!(
+                manifest.unknown_keys(),
+                ["pkg.rust.target.x86_64-unknown-linux-gnu.release_notes_url"]
+            );
+
+            let bytes = manifest.to_vec();
+            assert_eq!(parse(&bytes), parse(document));
+            assert_eq!(keys(&parse(&bytes), ""), keys(&parse(document), ""));
+        }
+
+        #[test]
+        fn unknown_fields_keep_their_position() {
+            let document = br#"
+                future = "a"
+                manifest-version = "2"
+                date = "2022-04-07"
+
+                [pkg.rust]
+                version = "1.60.0"
+                channel = "stable"
+
+                [pkg.rust.target.x86_64-unknown-linux-gnu]
+                available = true
+                comment = "b"
+                url = "https://example.org/dist/2022-04-07/rust.tar.gz"
+                hash = "69e3a84b6e3fa6200d16da08a79f710b40ad6f76318b1d8a08bce6df4a9d514c"
+
+                [artifacts.source-code]
+                url = "https://example.org/dist/2022-04-07/rustc-src.tar.gz"
+
+                [profiles]
+                minimal = ["rustc"]
+            "#;
+
+            let manifest = Manifest::from_slice(document).expect("invalid manifest");
+            assert_eq!(
+                manifest.unknown_keys(),
+                [
+                    "future",
+                    "artifacts",
+                    "pkg.rust.channel",
+                    "pkg.rust.target.x86_64-unknown-linux-gnu.comment"
+                ]
+            );
+
+            let bytes = manifest.to_vec();
+            assert_eq!(parse(&bytes), parse(document));
+            assert_eq!(keys(&parse(&bytes), ""), keys(&parse(document), ""));
+        }
+
+        #[test]
+        fn unknown_urls() {
+            let manifest = Manifest::from_slice(
+                br#"
+                    date = "2022-04-07"
+                    homepage_url = "https://example.org/index.html"
+
+                    [pkg.rust.target.x86_64-unknown-linux-gnu]
+                    available = true
+                    url = "https://example.org/dist/2022-04-07/rust.tar.gz"
+                    signature_url = "https://example.org/dist/2022-04-07/rust.tar.gz.asc"
+
+                    [[artifacts.source-code.target."*"]]
+                    url = "https://example.org/dist/2022-04-07/rustc-src.tar.gz"
+                "#,
+            )
+            .expect("invalid manifest");
+
+            assert_eq!(
+                manifest.unknown_urls(),
+                [
+                    ("homepage_url".to_string(), "https://example.org/index.html"),
+                    (
+                        "artifacts.source-code.target.*[0].url".to_string(),
+                        "https://example.org/dist/2022-04-07/rustc-src.tar.gz"
+                    ),
+                    (
+                        "pkg.rust.target.x86_64-unknown-linux-gnu.signature_url".to_string(),
+                        "https://example.org/dist/2022-04-07/rust.tar.gz.asc"
+                    ),
+                ]
+            );
         }
     }
 }
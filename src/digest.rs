@@ -4,9 +4,13 @@ use std::{
     fmt::{self, Display, Formatter},
     io,
     path::Path,
+    str::FromStr,
 };
 use tokio::{fs::File, io::AsyncReadExt};
 
+/// The size of the buffer used to read files.
+const BUFFER_SIZE: usize = 64 * 1024;
+
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
 pub struct Sha256(#[serde(with = "hex")] pub [u8; 32]);
 
@@ -18,10 +22,7 @@ impl Sha256 {
     }
 
     pub async fn from_file(path: &Path) -> Result<Self, io::Error> {
-        let mut bytes = Vec::new();
-        let mut file = File::open(path).await?;
-        file.read_to_end(&mut bytes).await?;
-        Ok(Self::from_slice(&bytes))
+        Ok(Hasher::from_file(path).await?.finalise())
     }
 }
 
@@ -30,3 +31,43 @@ impl Display for Sha256 {
         write!(f, "{}", hex::encode(self.0))
     }
 }
+
+impl FromStr for Sha256 {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(Self(bytes))
+    }
+}
+
+/// A hasher can be used to incrementally compute a checksum.
+#[derive(Clone, Debug, Default)]
+pub struct Hasher(sha2::Sha256);
+
+impl Hasher {
+    /// Creates a hasher that has been updated with the contents of the file at `path`.
+    pub async fn from_file(path: &Path) -> Result<Self, io::Error> {
+        let mut hasher = Self::default();
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let mut file = File::open(path).await?;
+        loop {
+            match file.read(&mut buffer).await? {
+                0 => return Ok(hasher),
+                n => hasher.update(&buffer[..n]),
+            }
+        }
+    }
+
+    #[inline]
+    pub fn update(&mut self, s: &[u8]) {
+        self.0.update(s);
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn finalise(self) -> Sha256 {
+        Sha256(self.0.finalize().into())
+    }
+}
@@ -1,8 +1,26 @@
-use bytes::Bytes;
+use crate::{
+    digest::{Hasher, Sha256},
+    extension::Path as PathExtension,
+};
+use rand::Rng;
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE},
+    StatusCode,
+};
 use std::{
-    convert::Into,
     fmt::{self, Display, Formatter},
+    future::Future,
+    io,
+    num::NonZeroUsize,
+    path::Path,
+    time::Duration,
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
 };
+use tracing::{info, warn};
 use url::Url;
 
 #[derive(Clone, Debug, Default)]
@@ -11,14 +29,180 @@ pub struct HttpDownloader {
 }
 
 impl HttpDownloader {
+    /// Downloads `source` to `destination` and returns the checksum of the downloaded file.
+    ///
+    /// The response is streamed to `destination` so that large files are never buffered in memory.
+    /// If `destination` already exists then it's assumed to be a partial download that is resumed
+    /// when the server supports range requests.
+    pub async fn download(&self, source: Url, destination: &Path) -> Result<Sha256, Error> {
+        let offset = match fs::metadata(destination).await {
+            // A partial download with other links shares its contents with another file (eg. an
+            // archive that was hard linked by the file downloader) so it's discarded rather than
+            // being written to.
+            #[cfg(unix)]
+            Ok(metadata) if std::os::unix::fs::MetadataExt::nlink(&metadata) > 1 => {
+                fs::remove_file(destination).await?;
+                0
+            }
+            Ok(metadata) => metadata.len(),
+            Err(error) => {
+                use io::ErrorKind::NotFound;
+
+                if error.kind() != NotFound {
+                    return Err(error.into());
+                }
+
+                0
+            }
+        };
+
+        let mut request = self.client.get(source.clone());
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+
+        let mut response = request.send().await?;
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // The partial download can't be resumed so the file is downloaded from the beginning.
+            response = self.client.get(source).send().await?;
+        }
+
+        let mut response = response.error_for_status()?;
+        let (mut file, mut hasher) = if response.status() == StatusCode::PARTIAL_CONTENT {
+            let range = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|range| range.to_str().ok());
+
+            // The partial download is discarded so that the next attempt starts from the beginning
+            // rather than failing in the same way.
+            if !range.is_some_and(|range| range.starts_with(&format!("bytes {offset}-"))) {
+                fs::remove_file(destination).await?;
+                return Err(Error::BadRange);
+            }
+
+            info!(offset, "resumed download");
+            (
+                OpenOptions::new().append(true).open(destination).await?,
+                Hasher::from_file(destination).await?,
+            )
+        } else {
+            (File::create(destination).await?, Hasher::default())
+        };
+
+        while let Some(chunk) = response.chunk().await? {
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+
+        file.flush().await?;
+        Ok(hasher.finalise())
+    }
+
+    /// Fetches the contents of `source`.
+    pub async fn fetch(&self, source: Url) -> Result<Vec<u8>, Error> {
+        let response = self.client.get(source).send().await?.error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Returns the size of `source` in bytes or `None` if the server doesn't describe it.
+    ///
+    /// The size is requested without downloading the contents.
+    pub async fn size(&self, source: Url) -> Result<Option<u64>, Error> {
+        let response = self.client.head(source).send().await?.error_for_status()?;
+
+        // The header is parsed because the body of a response to a HEAD request is always empty.
+        Ok(response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok()))
+    }
+}
+
+/// The size of the buffer used to copy files.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A downloader for files on the local file system.
+#[derive(Clone, Debug, Default)]
+pub struct FileDownloader {
+    link: bool,
+}
+
+impl FileDownloader {
+    /// Creates a file downloader that hard links files rather than copying them when `link` is
+    /// set.
     #[inline]
-    pub async fn download(&self, source: Url) -> Result<Bytes, reqwest::Error> {
-        self.client.get(source).send().await?.bytes().await
+    #[must_use]
+    pub fn new(link: bool) -> Self {
+        Self { link }
+    }
+
+    /// Copies `source` to `destination` and returns the checksum of the copied file.
+    ///
+    /// Files are copied if they can't be hard linked (eg. they reside on another file system or
+    /// have too many links).
+    pub async fn download(&self, source: Url, destination: &Path) -> Result<Sha256, Error> {
+        let path = source
+            .to_file_path()
+            .map_err(|()| Error::InvalidFileUrl(source.clone()))?;
+
+        // Partial downloads are discarded rather than truncated because hard links can't replace
+        // existing files and a previous download might have hard linked another file into place.
+        destination.remove_file_if_exists().await?;
+
+        if self.link {
+            match fs::hard_link(&path, destination).await {
+                Ok(()) => return Ok(Sha256::from_file(destination).await?),
+                Err(error) => warn!(
+                    error = error.to_string().as_str(),
+                    "copying file that can't be hard linked"
+                ),
+            }
+        }
+
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let mut hasher = Hasher::default();
+        let mut input = File::open(path).await?;
+        let mut output = File::create(destination).await?;
+        loop {
+            match input.read(&mut buffer).await? {
+                0 => break,
+                n => {
+                    hasher.update(&buffer[..n]);
+                    output.write_all(&buffer[..n]).await?;
+                }
+            }
+        }
+
+        output.flush().await?;
+        Ok(hasher.finalise())
+    }
+
+    /// Reads the contents of `source`.
+    pub async fn fetch(&self, source: Url) -> Result<Vec<u8>, Error> {
+        let path = source
+            .to_file_path()
+            .map_err(|()| Error::InvalidFileUrl(source.clone()))?;
+
+        Ok(fs::read(path).await?)
+    }
+
+    /// Returns the size of `source` in bytes.
+    pub async fn size(&self, source: Url) -> Result<Option<u64>, Error> {
+        let path = source
+            .to_file_path()
+            .map_err(|()| Error::InvalidFileUrl(source.clone()))?;
+
+        Ok(Some(fs::metadata(path).await?.len()))
     }
 }
 
 #[derive(Debug)]
 pub enum Error {
+    BadRange,
+    FileSystem(io::Error),
+    InvalidFileUrl(Url),
     Reqwest(reqwest::Error),
     UnsupportedUrlScheme(String),
 }
@@ -26,8 +210,11 @@ pub enum Error {
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
+            Self::BadRange => write!(f, "server responded with an unexpected range"),
+            Self::FileSystem(error) => error.fmt(f),
+            Self::InvalidFileUrl(url) => write!(f, "invalid file url '{url}'"),
             Self::Reqwest(error) => error.fmt(f),
-            Self::UnsupportedUrlScheme(scheme) => write!(f, "unsupported url scheme '{}'", scheme),
+            Self::UnsupportedUrlScheme(scheme) => write!(f, "unsupported url scheme '{scheme}'"),
         }
     }
 }
@@ -35,29 +222,511 @@ impl Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
+            Self::FileSystem(error) => error.source(),
             Self::Reqwest(error) => error.source(),
-            Self::UnsupportedUrlScheme(_) => None,
+            Self::BadRange | Self::InvalidFileUrl(_) | Self::UnsupportedUrlScheme(_) => None,
+        }
+    }
+}
+
+impl Error {
+    /// Returns whether or not the error is likely to be resolved by trying again.
+    ///
+    /// Timeouts, connection failures and server errors are transient. Client errors (eg. a missing
+    /// file) are not.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Reqwest(error) => match error.status() {
+                Some(status) => {
+                    status.is_server_error()
+                        || status == StatusCode::REQUEST_TIMEOUT
+                        || status == StatusCode::TOO_MANY_REQUESTS
+                }
+                None => {
+                    error.is_timeout()
+                        || error.is_connect()
+                        || error.is_request()
+                        || error.is_body()
+                }
+            },
+            // The partial download is discarded when the range is unexpected so a retry restarts it.
+            Self::BadRange => true,
+            Self::FileSystem(_) | Self::InvalidFileUrl(_) | Self::UnsupportedUrlScheme(_) => false,
+        }
+    }
+
+    /// Returns whether or not the error is caused by a missing file.
+    #[must_use]
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Self::FileSystem(error) => error.kind() == io::ErrorKind::NotFound,
+            Self::Reqwest(error) => error.status() == Some(StatusCode::NOT_FOUND),
+            Self::BadRange | Self::InvalidFileUrl(_) | Self::UnsupportedUrlScheme(_) => false,
         }
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::FileSystem(error)
+    }
+}
+
 impl From<reqwest::Error> for Error {
     fn from(error: reqwest::Error) -> Self {
         Self::Reqwest(error)
     }
 }
 
+/// Describes how downloads that fail with transient errors are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /// The maximum number of attempts, including the first.
+    pub attempts: NonZeroUsize,
+    /// The delay before the first retry. The delay is doubled after every retry.
+    pub backoff: Duration,
+    /// The maximum random delay that is added to every backoff.
+    pub jitter: Duration,
+}
+
+impl Retry {
+    /// Returns the delay before the attempt that follows `attempt`.
+    fn delay(&self, attempt: usize) -> Duration {
+        let exponent = u32::try_from(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        let backoff = self.backoff.saturating_mul(2_u32.saturating_pow(exponent));
+
+        backoff.saturating_add(rand::thread_rng().gen_range(Duration::ZERO..=self.jitter))
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: NonZeroUsize::new(3).expect("zero attempts"),
+            backoff: Duration::from_secs(1),
+            jitter: Duration::from_secs(1),
+        }
+    }
+}
+
 /// A downloader can be used to download files.
 #[derive(Debug, Default)]
 pub struct Downloader {
+    file: FileDownloader,
     http: HttpDownloader,
+    retry: Retry,
 }
 
 impl Downloader {
-    pub async fn download(&self, source: Url) -> Result<Bytes, Error> {
-        match source.scheme() {
-            "http" | "https" => self.http.download(source).await.map_err(Into::into),
-            scheme => Err(Error::UnsupportedUrlScheme(scheme.to_string())),
+    /// Creates a downloader that downloads file URLs using `file` and retries failed downloads
+    /// according to `retry`.
+    #[inline]
+    #[must_use]
+    pub fn new(file: FileDownloader, retry: Retry) -> Self {
+        Self {
+            file,
+            http: HttpDownloader::default(),
+            retry,
+        }
+    }
+
+    /// Downloads `source` to `destination` and returns the checksum of the downloaded file.
+    ///
+    /// Downloads are resumed if `destination` already exists and the source supports it. Downloads
+    /// that fail with transient errors are retried.
+    pub async fn download(&self, source: Url, destination: &Path) -> Result<Sha256, Error> {
+        self.retry(&source, || async {
+            match source.scheme() {
+                "file" => self.file.download(source.clone(), destination).await,
+                "http" | "https" => self.http.download(source.clone(), destination).await,
+                scheme => Err(Error::UnsupportedUrlScheme(scheme.to_string())),
+            }
+        })
+        .await
+    }
+
+    /// Fetches the contents of `source`.
+    ///
+    /// The contents are buffered in memory so this should only be used for small files (eg.
+    /// manifests). Fetches that fail with transient errors are retried.
+    pub async fn fetch(&self, source: Url) -> Result<Vec<u8>, Error> {
+        self.retry(&source, || async {
+            match source.scheme() {
+                "file" => self.file.fetch(source.clone()).await,
+                "http" | "https" => self.http.fetch(source.clone()).await,
+                scheme => Err(Error::UnsupportedUrlScheme(scheme.to_string())),
+            }
+        })
+        .await
+    }
+
+    /// Returns the size of `source` in bytes or `None` if it isn't known.
+    ///
+    /// Requests that fail with transient errors are retried.
+    pub async fn size(&self, source: Url) -> Result<Option<u64>, Error> {
+        self.retry(&source, || async {
+            match source.scheme() {
+                "file" => self.file.size(source.clone()).await,
+                "http" | "https" => self.http.size(source.clone()).await,
+                scheme => Err(Error::UnsupportedUrlScheme(scheme.to_string())),
+            }
+        })
+        .await
+    }
+
+    /// Repeatedly calls `f` until it succeeds, fails with an error that isn't transient, or the
+    /// maximum number of attempts is reached.
+    async fn retry<T, F, Fut>(&self, source: &Url, f: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(error) if error.is_transient() && attempt < self.retry.attempts.get() => {
+                    let delay = self.retry.delay(attempt);
+                    warn!(
+                        url = source.as_str(),
+                        attempt,
+                        delay = format!("{delay:?}").as_str(),
+                        error = error.to_string().as_str(),
+                        "retrying download"
+                    );
+
+                    time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    /// Serves `responses` to consecutive connections on a local listener. Returns the URL of the
+    /// listener along with a handle that yields the head of every request in lowercase.
+    fn serve(responses: Vec<String>) -> (Url, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind listener");
+        let address = listener.local_addr().expect("missing address");
+        let url = Url::parse(&format!("http://{address}/archive")).expect("invalid url");
+        let handle = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (mut stream, _) = listener.accept().expect("failed to accept connection");
+                    let mut head = Vec::new();
+                    let mut byte = [0; 1];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        stream
+                            .read_exact(&mut byte)
+                            .expect("failed to read request");
+                        head.push(byte[0]);
+                    }
+
+                    stream
+                        .write_all(response.as_bytes())
+                        .expect("failed to write response");
+
+                    String::from_utf8(head)
+                        .expect("invalid request")
+                        .to_lowercase()
+                })
+                .collect()
+        });
+
+        (url, handle)
+    }
+
+    /// Returns a response that closes the connection once `body` has been sent.
+    fn response(status: &str, headers: &[&str], body: &str) -> String {
+        let headers = headers
+            .iter()
+            .flat_map(|header| [*header, "\r\n"])
+            .collect::<String>();
+
+        format!(
+            "HTTP/1.1 {status}\r\nconnection: close\r\ncontent-length: {}\r\n{headers}\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Downloads from a server that replies with `responses` to a destination that contains
+    /// `partial`. Returns the result, the contents of the destination and the request heads.
+    async fn download(
+        partial: &str,
+        responses: Vec<String>,
+    ) -> (Result<Sha256, Error>, Option<Vec<u8>>, Vec<String>) {
+        let directory = tempfile::tempdir().expect("failed to create directory");
+        let destination = directory.path().join("archive");
+        fs::write(&destination, partial)
+            .await
+            .expect("failed to write partial download");
+
+        let (url, requests) = serve(responses);
+        let result = HttpDownloader::default().download(url, &destination).await;
+        let contents = fs::read(&destination).await.ok();
+        (result, contents, requests.join().expect("server panicked"))
+    }
+
+    #[tokio::test]
+    async fn resume_partial_content() {
+        let (result, contents, requests) = download(
+            "hello ",
+            vec![response(
+                "206 Partial Content",
+                &["content-range: bytes 6-10/11"],
+                "world",
+            )],
+        )
+        .await;
+
+        assert_eq!(
+            result.expect("failed to download"),
+            Sha256::from_slice(b"hello world")
+        );
+        assert_eq!(contents.as_deref(), Some(&b"hello world"[..]));
+        assert!(requests[0].contains("range: bytes=6-\r\n"));
+    }
+
+    #[tokio::test]
+    async fn restart_when_range_is_ignored() {
+        let (result, contents, _) =
+            download("hello ", vec![response("200 OK", &[], "hello world")]).await;
+
+        assert_eq!(
+            result.expect("failed to download"),
+            Sha256::from_slice(b"hello world")
+        );
+        assert_eq!(contents.as_deref(), Some(&b"hello world"[..]));
+    }
+
+    #[tokio::test]
+    async fn restart_when_range_is_not_satisfiable() {
+        let (result, contents, requests) = download(
+            "hello world!",
+            vec![
+                response(
+                    "416 Range Not Satisfiable",
+                    &["content-range: bytes */11"],
+                    "",
+                ),
+                response("200 OK", &[], "hello world"),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            result.expect("failed to download"),
+            Sha256::from_slice(b"hello world")
+        );
+        assert_eq!(contents.as_deref(), Some(&b"hello world"[..]));
+        assert!(requests[0].contains("range: bytes=12-\r\n"));
+        assert!(!requests[1].contains("range:"));
+    }
+
+    #[tokio::test]
+    async fn discard_partial_download_with_bad_range() {
+        let (result, contents, _) = download(
+            "hello ",
+            vec![response(
+                "206 Partial Content",
+                &["content-range: bytes 0-10/11"],
+                "hello world",
+            )],
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::BadRange)));
+        assert_eq!(contents, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn restart_when_partial_download_is_shared() {
+        let directory = tempfile::tempdir().expect("failed to create directory");
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        fs::write(&source, "hello ")
+            .await
+            .expect("failed to write source");
+
+        fs::hard_link(&source, &destination)
+            .await
+            .expect("failed to link destination");
+
+        let (url, requests) = serve(vec![response("200 OK", &[], "hello world")]);
+        HttpDownloader::default()
+            .download(url, &destination)
+            .await
+            .expect("failed to download");
+
+        let requests = requests.join().expect("server panicked");
+        assert!(!requests[0].contains("range:"));
+        assert_eq!(
+            fs::read(&source).await.expect("failed to read source"),
+            b"hello "
+        );
+        assert_eq!(
+            fs::read(&destination)
+                .await
+                .expect("failed to read destination"),
+            b"hello world"
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_replaces_hard_linked_partial_download() {
+        let directory = tempfile::tempdir().expect("failed to create directory");
+        let source = directory.path().join("source");
+        let destination = directory.path().join("destination");
+        fs::write(&source, b"archive")
+            .await
+            .expect("failed to write source");
+
+        fs::hard_link(&source, &destination)
+            .await
+            .expect("failed to link destination");
+
+        let url = Url::from_file_path(&source).expect("invalid path");
+        let checksum = FileDownloader::new(false)
+            .download(url, &destination)
+            .await
+            .expect("failed to download");
+
+        assert_eq!(checksum, Sha256::from_slice(b"archive"));
+        assert_eq!(
+            fs::read(&source).await.expect("failed to read source"),
+            b"archive"
+        );
+        assert_eq!(
+            fs::read(&destination)
+                .await
+                .expect("failed to read destination"),
+            b"archive"
+        );
+    }
+
+    /// Returns a downloader that retries `attempts` times without waiting.
+    fn retrying(attempts: usize) -> Downloader {
+        Downloader::new(
+            FileDownloader::default(),
+            Retry {
+                attempts: NonZeroUsize::new(attempts).expect("zero attempts"),
+                backoff: Duration::ZERO,
+                jitter: Duration::ZERO,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn status_errors_are_classified() {
+        for (status, transient) in [
+            ("500 Internal Server Error", true),
+            ("503 Service Unavailable", true),
+            ("408 Request Timeout", true),
+            ("429 Too Many Requests", true),
+            ("404 Not Found", false),
+            ("403 Forbidden", false),
+        ] {
+            let (url, requests) = serve(vec![response(status, &[], "")]);
+            let error = HttpDownloader::default()
+                .fetch(url)
+                .await
+                .expect_err("fetch should fail");
+
+            requests.join().expect("server panicked");
+            assert_eq!(error.is_transient(), transient, "{status}");
         }
     }
+
+    #[tokio::test]
+    async fn timeouts_are_transient() {
+        // The listener never accepts the connection so the request times out.
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind listener");
+        let address = listener.local_addr().expect("missing address");
+        let downloader = HttpDownloader {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(100))
+                .build()
+                .expect("failed to build client"),
+        };
+
+        let error = downloader
+            .fetch(Url::parse(&format!("http://{address}/")).expect("invalid url"))
+            .await
+            .expect_err("fetch should time out");
+
+        assert!(matches!(&error, Error::Reqwest(error) if error.is_timeout()));
+        assert!(error.is_transient());
+    }
+
+    #[test]
+    fn other_errors_are_permanent() {
+        let url = Url::parse("ftp://example.org/").expect("invalid url");
+        assert!(Error::BadRange.is_transient());
+        assert!(!Error::FileSystem(io::ErrorKind::NotFound.into()).is_transient());
+        assert!(!Error::InvalidFileUrl(url).is_transient());
+        assert!(!Error::UnsupportedUrlScheme("ftp".to_string()).is_transient());
+    }
+
+    #[test]
+    fn delay_without_backoff_or_jitter() {
+        let retry = Retry {
+            attempts: NonZeroUsize::new(3).expect("zero attempts"),
+            backoff: Duration::ZERO,
+            jitter: Duration::ZERO,
+        };
+
+        assert_eq!(retry.delay(1), Duration::ZERO);
+        assert_eq!(retry.delay(2), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (url, requests) = serve(vec![
+            response("503 Service Unavailable", &[], ""),
+            response("500 Internal Server Error", &[], ""),
+            response("200 OK", &[], "manifest"),
+        ]);
+
+        let contents = retrying(3).fetch(url).await.expect("failed to fetch");
+        assert_eq!(contents, b"manifest");
+        assert_eq!(requests.join().expect("server panicked").len(), 3);
+    }
+
+    #[tokio::test]
+    async fn attempts_are_limited() {
+        let (url, requests) = serve(vec![
+            response("503 Service Unavailable", &[], ""),
+            response("503 Service Unavailable", &[], ""),
+        ]);
+
+        let error = retrying(2).fetch(url).await.expect_err("fetch should fail");
+        assert!(
+            matches!(error, Error::Reqwest(error) if error.status() == Some(StatusCode::SERVICE_UNAVAILABLE))
+        );
+        assert_eq!(requests.join().expect("server panicked").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_never_retried() {
+        // The listener is closed after the first response so a retry would fail to connect.
+        let (url, requests) = serve(vec![response("404 Not Found", &[], "")]);
+        let error = retrying(3).fetch(url).await.expect_err("fetch should fail");
+        assert!(error.is_not_found());
+        assert!(
+            matches!(error, Error::Reqwest(error) if error.status() == Some(StatusCode::NOT_FOUND))
+        );
+        assert_eq!(requests.join().expect("server panicked").len(), 1);
+    }
 }
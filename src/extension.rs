@@ -1,15 +1,24 @@
 use async_trait::async_trait;
-use std::io;
-use tokio::fs;
+use std::{io, path::PathBuf, process};
+use tokio::{fs, io::AsyncWriteExt};
 
 pub trait Url {
     /// Returns the file name.
     #[must_use]
     fn file_name(&self) -> Option<&str>;
+
+    /// Returns a copy of the URL with `suffix` appended to the path.
+    #[must_use]
+    fn with_path_suffix(&self, suffix: &str) -> url::Url;
 }
 
 impl Url for url::Url {
-    #[must_use]
+    fn with_path_suffix(&self, suffix: &str) -> url::Url {
+        let mut url = self.clone();
+        url.set_path(&format!("{}{suffix}", self.path()));
+        url
+    }
+
     fn file_name(&self) -> Option<&str> {
         if self.cannot_be_a_base() {
             return None;
@@ -25,6 +34,31 @@ impl Url for url::Url {
 pub trait Path {
     /// Returns whether or not the path exists.
     async fn async_try_exists(&self) -> Result<bool, io::Error>;
+
+    /// Returns a copy of the path with `suffix` appended to the file name.
+    #[must_use]
+    fn with_suffix(&self, suffix: &str) -> PathBuf;
+
+    /// Returns the path of a temporary sibling that's unique to the process.
+    #[must_use]
+    fn temporary(&self) -> PathBuf;
+
+    /// Removes the file at the path unless it doesn't exist.
+    async fn remove_file_if_exists(&self) -> Result<(), io::Error>;
+
+    /// Synchronises the directory at the path so that changes to its entries survive a crash.
+    async fn sync_directory(&self) -> Result<(), io::Error>;
+
+    /// Synchronises the file at `source` and renames it to the path.
+    ///
+    /// The parent directory is also synchronised so that the rename survives a crash.
+    async fn replace_with(&self, source: &std::path::Path) -> Result<(), io::Error>;
+
+    /// Writes `contents` to the file at the path atomically.
+    ///
+    /// The contents are written to a temporary sibling that replaces the file once it's complete so
+    /// that a partial file is never visible at the path.
+    async fn write_atomic(&self, contents: &[u8]) -> Result<(), io::Error>;
 }
 
 #[async_trait]
@@ -41,4 +75,61 @@ impl Path for std::path::Path {
             }
         }
     }
+
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = self.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    fn temporary(&self) -> PathBuf {
+        let name = self
+            .file_name()
+            .expect("file has no name")
+            .to_string_lossy();
+        self.with_file_name(format!(".{name}.{}.tmp", process::id()))
+    }
+
+    async fn remove_file_if_exists(&self) -> Result<(), io::Error> {
+        match fs::remove_file(self).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    async fn sync_directory(&self) -> Result<(), io::Error> {
+        // Directories can't be opened on some platforms so they're only synchronised on Unix.
+        #[cfg(unix)]
+        fs::File::open(self).await?.sync_all().await?;
+
+        Ok(())
+    }
+
+    async fn replace_with(&self, source: &std::path::Path) -> Result<(), io::Error> {
+        fs::File::open(source).await?.sync_all().await?;
+        fs::rename(source, self).await?;
+        self.parent()
+            .expect("file has no parent")
+            .sync_directory()
+            .await
+    }
+
+    async fn write_atomic(&self, contents: &[u8]) -> Result<(), io::Error> {
+        let temporary = self.temporary();
+        let result = async {
+            let mut file = fs::File::create(&temporary).await?;
+            file.write_all(contents).await?;
+            file.flush().await?;
+
+            self.replace_with(&temporary).await
+        }
+        .await;
+
+        if result.is_err() {
+            // The temporary file might not exist so any failure to remove it is ignored.
+            let _ = fs::remove_file(&temporary).await;
+        }
+
+        result
+    }
 }
@@ -0,0 +1,161 @@
+use crate::{digest::Sha256, extension::Path as PathExtension};
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use tokio::fs;
+use tracing::warn;
+
+/// Describes a file when it was last hashed.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Entry {
+    size: u64,
+    modified: SystemTime,
+    hash: Sha256,
+}
+
+/// An index of the checksums of files in a cache.
+///
+/// A file is only rehashed if its size or modification time has changed since it was last hashed.
+/// Paths are relative to the root of the cache.
+#[derive(Debug)]
+pub struct Index {
+    root: PathBuf,
+    entries: Mutex<AHashMap<PathBuf, Entry>>,
+}
+
+impl Index {
+    /// Creates an empty index for the cache at `root`.
+    #[must_use]
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Reads the index for the cache at `root` from the file at `path`.
+    ///
+    /// An empty index is returned if the file doesn't exist or can't be parsed because the index
+    /// can always be rebuilt.
+    pub async fn read(root: PathBuf, path: &Path) -> Result<Self, io::Error> {
+        let bytes = match fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Self::new(root)),
+            Err(error) => return Err(error),
+        };
+
+        let entries = serde_json::from_slice(&bytes).unwrap_or_else(|error| {
+            warn!(%error, "discarded index");
+            AHashMap::new()
+        });
+
+        Ok(Self {
+            root,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Serialises the index into a vector of bytes.
+    ///
+    /// Entries are sorted by path so that the serialisation is deterministic.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let entries = self.entries.lock().expect("index is poisoned");
+        let entries = entries.iter().collect::<BTreeMap<_, _>>();
+        serde_json::to_vec(&entries).expect("failed to serialise index")
+    }
+
+    /// Discards the entries of files that no longer exist.
+    pub async fn retain_existing(&self) -> Result<(), io::Error> {
+        let paths = self
+            .entries
+            .lock()
+            .expect("index is poisoned")
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for path in paths {
+            if !self.root.join(&path).async_try_exists().await? {
+                self.entries
+                    .lock()
+                    .expect("index is poisoned")
+                    .remove(&path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the checksum of the file at `path` or `None` if it doesn't exist.
+    ///
+    /// The file is hashed if it has changed since it was last hashed or if `paranoid` is true.
+    pub async fn hash(&self, path: &Path, paranoid: bool) -> Result<Option<Sha256>, io::Error> {
+        let absolute = self.root.join(path);
+        let metadata = match fs::metadata(&absolute).await {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        if !paranoid {
+            if let Some(hash) = self.lookup(path, &metadata)? {
+                return Ok(Some(hash));
+            }
+        }
+
+        // The metadata from before the file was hashed is recorded so that changes made while it
+        // was being hashed are detected.
+        let hash = Sha256::from_file(&absolute).await?;
+        self.record(path, &metadata, hash)?;
+        Ok(Some(hash))
+    }
+
+    /// Returns the recorded checksum of the file at `path` without hashing it.
+    ///
+    /// `None` is returned if the file doesn't exist or has changed since it was last hashed.
+    pub async fn recorded(&self, path: &Path) -> Result<Option<Sha256>, io::Error> {
+        match fs::metadata(self.root.join(path)).await {
+            Ok(metadata) => self.lookup(path, &metadata),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Returns the recorded checksum of the file at `path` if `metadata` matches the recording.
+    fn lookup(&self, path: &Path, metadata: &Metadata) -> Result<Option<Sha256>, io::Error> {
+        let modified = metadata.modified()?;
+        let entries = self.entries.lock().expect("index is poisoned");
+        Ok(entries
+            .get(path)
+            .filter(|entry| entry.size == metadata.len() && entry.modified == modified)
+            .map(|entry| entry.hash))
+    }
+
+    /// Records that the file at `path` has the checksum `hash`.
+    pub async fn insert(&self, path: &Path, hash: Sha256) -> Result<(), io::Error> {
+        let metadata = fs::metadata(self.root.join(path)).await?;
+        self.record(path, &metadata, hash)
+    }
+
+    fn record(&self, path: &Path, metadata: &Metadata, hash: Sha256) -> Result<(), io::Error> {
+        let entry = Entry {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            hash,
+        };
+
+        self.entries
+            .lock()
+            .expect("index is poisoned")
+            .insert(path.to_path_buf(), entry);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    error,
+    fmt::{self, Display, Formatter},
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
+use tokio::{task, time};
+use tracing::{info, warn};
+
+/// The file, relative to the cache, that's locked while the cache is being changed.
+pub const LOCK_FILE: &str = ".rustdown/lock";
+
+/// The delay between attempts to acquire a lock that's held by another process.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum Error {
+    FileSystem(io::Error),
+    Locked(Option<u32>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileSystem(error) => error.fmt(f),
+            Self::Locked(Some(pid)) => write!(f, "cache is locked by process {pid}"),
+            Self::Locked(None) => write!(f, "cache is locked by another process"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::FileSystem(error) => error.source(),
+            Self::Locked(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::FileSystem(error)
+    }
+}
+
+/// Returns the identifier of the process recorded in the lock file or `None` if there isn't one.
+fn holder(file: &mut File) -> Result<Option<u32>, io::Error> {
+    let mut contents = String::new();
+    file.rewind()?;
+    file.read_to_string(&mut contents)?;
+    Ok(contents.trim().parse().ok())
+}
+
+/// An advisory lock on a cache that's held until it's dropped.
+///
+/// The lock is held by the operating system so it's released even if the process crashes. The lock
+/// file records the identifier of the process holding it and is cleared when the lock is released
+/// so that a lock left behind by a crashed process can be detected.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    fn try_acquire(path: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(path.parent().expect("file has no parent"))?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            // The lock file can't be read on platforms where locks are mandatory.
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::Locked(holder(&mut file).ok().flatten()))
+            }
+            Err(TryLockError::Error(error)) => return Err(error.into()),
+        }
+
+        if let Some(pid) = holder(&mut file)? {
+            warn!(pid, "recovered stale lock");
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", process::id())?;
+        file.sync_all()?;
+        Ok(Self { file })
+    }
+
+    /// Acquires the lock on the cache at `root`.
+    ///
+    /// If another process holds the lock then this waits for up to `timeout` before failing.
+    pub async fn acquire(root: &Path, timeout: Duration) -> Result<Self, Error> {
+        let path = root.join(LOCK_FILE);
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            let path = path.clone();
+            match task::spawn_blocking(move || Self::try_acquire(&path))
+                .await
+                .expect("panicked while locking cache")
+            {
+                Err(Error::Locked(pid)) if start.elapsed() < timeout => {
+                    if !waiting {
+                        info!(pid, "waiting for lock");
+                        waiting = true;
+                    }
+
+                    time::sleep(RETRY_INTERVAL).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // The lock file is cleared rather than deleted because another process might be waiting to
+        // lock it. Failures are ignored because the lock is released when the file is closed.
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn held_lock_reports_holder() {
+        let directory = tempfile::tempdir().expect("failed to create directory");
+        let _lock = Lock::acquire(directory.path(), Duration::ZERO)
+            .await
+            .expect("failed to lock cache");
+
+        let pid = process::id();
+        assert!(matches!(
+            Lock::acquire(directory.path(), Duration::ZERO).await,
+            Err(Error::Locked(Some(holder))) if holder == pid
+        ));
+    }
+
+    #[tokio::test]
+    async fn stale_lock_is_recovered() {
+        let directory = tempfile::tempdir().expect("failed to create directory");
+        let path = directory.path().join(LOCK_FILE);
+        fs::create_dir_all(path.parent().expect("file has no parent"))
+            .expect("failed to create state directory");
+        fs::write(&path, "4194304").expect("failed to write lock file");
+
+        let lock = Lock::acquire(directory.path(), Duration::ZERO)
+            .await
+            .expect("failed to recover lock");
+
+        assert_eq!(
+            fs::read_to_string(&path).expect("failed to read lock file"),
+            process::id().to_string()
+        );
+
+        drop(lock);
+        assert_eq!(
+            fs::read_to_string(&path).expect("failed to read lock file"),
+            ""
+        );
+    }
+}
@@ -6,29 +6,94 @@ mod channel;
 mod digest;
 mod download;
 mod extension;
+mod index;
+mod lock;
+mod pattern;
+mod signature;
+mod snapshot;
 
 use ahash::AHashMap;
-use cache::Cache;
-use channel::{manifest::Manifest, Channel};
+use cache::{Cache, Link, Options, Plan, Selection, Verification};
+use channel::{manifest::Compression, Channel};
 use clap::{
     error::ErrorKind::{TooFewValues, ValueValidation},
-    Arg, Command,
+    Arg, ArgMatches, Command,
 };
-use download::Downloader;
-use eyre::Result;
-use futures::{stream, StreamExt, TryStreamExt};
-use std::{env, iter::IntoIterator, num::NonZeroUsize, path::PathBuf, str::FromStr};
-use tokio::{fs::File, io::AsyncReadExt};
-use tracing::{info, Level};
+use download::{Downloader, FileDownloader, Retry};
+use eyre::{bail, Result};
+use itertools::Itertools;
+use lock::Lock;
+use signature::{Keyring, Signer};
+use snapshot::{Snapshots, CURRENT_LINK};
+use std::{
+    env, io,
+    iter::IntoIterator,
+    num::NonZeroUsize,
+    path::{self, Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::{info, warn, Level};
 use url::Url;
 
 #[derive(Debug)]
-struct Arguments {
+struct BuildArguments {
     path: PathBuf,
     host: Url,
-    channels: AHashMap<Channel, PathBuf>,
+    channels: AHashMap<Channel, Url>,
+    verification: Verification,
+    selection: Selection,
+    retry: Retry,
+    link: bool,
+    options: Options,
+    check: bool,
+    dry_run: bool,
+    snapshots: Option<NonZeroUsize>,
+}
+
+#[derive(Debug)]
+struct VerifyArguments {
+    path: PathBuf,
+    json: bool,
+    protected: Vec<String>,
+}
+
+#[derive(Debug)]
+struct PruneArguments {
+    path: PathBuf,
+    protected: Vec<String>,
+}
+
+#[derive(Debug)]
+enum Mode {
+    // The build arguments are boxed because they're significantly larger than the others.
+    Build(Box<BuildArguments>),
+    Verify(VerifyArguments),
+    Prune(PruneArguments),
+}
+
+#[derive(Debug)]
+struct Arguments {
+    mode: Mode,
     jobs: NonZeroUsize,
     log_level: Level,
+    wait: Duration,
+}
+
+/// Returns the protected path patterns.
+fn protected(matches: &ArgMatches) -> Vec<String> {
+    matches
+        .values_of("protect")
+        .into_iter()
+        .flatten()
+        .map(String::from)
+        .collect()
+}
+
+/// Parses a duration from a number of seconds.
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds = f64::from_str(s).map_err(|error| error.to_string())?;
+    Duration::try_from_secs_f64(seconds).map_err(|error| error.to_string())
 }
 
 #[derive(Debug)]
@@ -37,17 +102,53 @@ struct Parser<'a> {
 }
 
 impl<'a> Parser<'a> {
+    #[allow(clippy::too_many_lines)]
     fn new(ncpus: &'a str) -> Self {
         let command = Command::new(env!("CARGO_PKG_NAME"))
             .version(env!("CARGO_PKG_VERSION"))
             .author(env!("CARGO_PKG_AUTHORS"))
             .about(env!("CARGO_PKG_DESCRIPTION"))
+            .subcommand_negates_reqs(true)
+            .subcommand(
+                Command::new("verify")
+                    .about("Verifies an existing cache without downloading anything")
+                    .long_about("Verifies an existing cache without downloading anything. Every archive referenced by a cached manifest is hashed and missing, corrupt and unreferenced files are reported.")
+                    .arg(
+                        Arg::new("path")
+                            .takes_value(true)
+                            .required(true)
+                            .help("The path of the cache"),
+                    )
+                    .arg(
+                        Arg::new("json")
+                            .long("json")
+                            .help("Prints a report in JSON format"),
+                    ),
+            )
+            .subcommand(
+                Command::new("prune")
+                    .about("Deletes files that aren't referenced by the manifests in an existing cache")
+                    .long_about("Deletes files that aren't referenced by the manifests in an existing cache. Partial downloads of referenced archives are preserved and nothing is deleted if a manifest can't be parsed.")
+                    .arg(
+                        Arg::new("path")
+                            .takes_value(true)
+                            .required(true)
+                            .help("The path of the cache"),
+                    ),
+            )
+            // Channels can be interleaved with options so they share a positional with the path,
+            // which is the last value.
+            .override_usage("rustdown [OPTIONS] [CHANNEL]... <PATH>\n    rustdown <SUBCOMMAND>")
             .arg(
-                Arg::new("path")
+                Arg::new("channel")
+                    .index(1)
                     .takes_value(true)
-                    .validator(|p| Url::from_directory_path(p).map_err(|_| "invalid path"))
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
                     .required(true)
-                    .help("The path of the cache"),
+                    .value_name("CHANNEL")
+                    .help("Channels to cache (eg. stable:1.60.0 or nightly:2022-04-01) followed by the path of the cache")
+                    .long_help("Channels to cache (eg. stable:1.60.0 or nightly:2022-04-01) followed by the path of the cache. Channel manifests are fetched from the upstream distribution server."),
             )
             .arg(
                 Arg::new("host")
@@ -57,17 +158,159 @@ impl<'a> Parser<'a> {
                     .help("The URL describing where the cache will be hosted")
                     .long_help("The URL describing where the cache will be hosted. The file system location will be used when this argument is not provided."),
             )
+            .arg(
+                Arg::new("upstream")
+                    .short('u')
+                    .long("upstream")
+                    .takes_value(true)
+                    .default_value("https://static.rust-lang.org/")
+                    .validator(Url::parse)
+                    .help("The URL of the distribution server that channel manifests are fetched from"),
+            )
             .arg(
                 Arg::new("manifest")
                     .short('m')
                     .long("manifest")
                     .takes_value(true)
-                    .multiple_values(true)
-                    .min_values(2)
+                    .number_of_values(2)
                     .multiple_occurrences(true)
                     .value_names(&["PATH", "CHANNEL"])
-                    .required(true)
-                    .help("The path to the channel manifest"),
+                    .help("The path to a channel manifest")
+                    .long_help("The path to a channel manifest. This can be used to cache a channel with a manifest that isn't hosted by the upstream distribution server."),
+            )
+            .arg(
+                Arg::new("verify_checksums")
+                    .long("verify-checksums")
+                    .help("Verifies manifests using the checksum files that accompany them")
+                    .long_help("Verifies manifests using the checksum files that accompany them. The checksum file of a manifest has the same URL with an additional .sha256 extension."),
+            )
+            .arg(
+                Arg::new("keyring")
+                    .short('k')
+                    .long("keyring")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .help("Verifies manifests using the signatures that accompany them")
+                    .long_help("Verifies manifests using the signatures that accompany them. Signatures are only trusted if they're made by a public key in the keyring at PATH. The signature of a manifest has the same URL with an additional .asc extension. A bad or missing signature aborts the build."),
+            )
+            .arg(
+                Arg::new("target")
+                    .short('t')
+                    .long("target")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .value_name("TRIPLE")
+                    .help("A target to cache")
+                    .long_help("A target to cache. Every target is cached when this argument is not provided. Target independent artefacts are always cached."),
+            )
+            .arg(
+                Arg::new("package")
+                    .short('p')
+                    .long("package")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .value_name("NAME")
+                    .help("A package to cache")
+                    .long_help("A package to cache. Every package is cached when neither this argument nor a profile is provided. The components and extensions of the package are also cached. The rust package is always cached because it describes the components of a toolchain. An unknown package aborts the build."),
+            )
+            .arg(
+                Arg::new("profile")
+                    .long("profile")
+                    .takes_value(true)
+                    .value_name("NAME")
+                    .help("A profile describing the packages to cache")
+                    .long_help("A profile describing the packages to cache. Profiles are resolved using the profiles described by each manifest."),
+            )
+            .arg(
+                Arg::new("compression")
+                    .short('c')
+                    .long("compression")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .validator(Compression::from_str)
+                    .value_name("FORMAT")
+                    .help("A compression format to cache (eg. gz or xz)")
+                    .long_help("A compression format to cache (eg. gz or xz). Every format is cached when this argument is not provided. Formats other than gz and xz are recognised by the <FORMAT>_url and <FORMAT>_hash fields of artefacts. Artefacts without an archive in any of the formats are marked as unavailable."),
+            )
+            .arg(
+                Arg::new("attempts")
+                    .long("attempts")
+                    .takes_value(true)
+                    .default_value("3")
+                    .validator(NonZeroUsize::from_str)
+                    .help("The maximum number of attempts to download a file")
+                    .long_help("The maximum number of attempts to download a file. Only timeouts, connection failures and server errors are retried."),
+            )
+            .arg(
+                Arg::new("backoff")
+                    .long("backoff")
+                    .takes_value(true)
+                    .default_value("1")
+                    .validator(parse_seconds)
+                    .value_name("SECONDS")
+                    .help("The delay before retrying a download")
+                    .long_help("The delay before retrying a download. The delay is doubled after every retry."),
+            )
+            .arg(
+                Arg::new("jitter")
+                    .long("jitter")
+                    .takes_value(true)
+                    .default_value("1")
+                    .validator(parse_seconds)
+                    .value_name("SECONDS")
+                    .help("The maximum random delay added before retrying a download"),
+            )
+            .arg(
+                Arg::new("hard_link")
+                    .long("hard-link")
+                    .help("Hard links artefacts with file URLs into the cache")
+                    .long_help("Hard links artefacts with file URLs into the cache rather than copying them. Artefacts are copied if they can't be hard linked."),
+            )
+            .arg(
+                Arg::new("archive_checksums")
+                    .long("archive-checksums")
+                    .help("Writes checksum files alongside archives")
+                    .long_help("Writes checksum files alongside archives. Checksum files are always written alongside manifests. A checksum file has the same path as the file it describes with an additional .sha256 extension."),
+            )
+            .arg(
+                Arg::new("signing_key")
+                    .long("signing-key")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .help("The path to a secret key used to sign manifests")
+                    .long_help("The path to a secret key used to sign manifests. The key can be either binary or ASCII armoured and can't be protected by a passphrase. A signature has the same path as the manifest it describes with an additional .asc extension."),
+            )
+            .arg(
+                Arg::new("no_prune")
+                    .long("no-prune")
+                    .help("Only adds files to the cache")
+                    .long_help("Only adds files to the cache. Files that aren't tracked by the channels being cached are not pruned so channels can be added to a cache over time. The prune subcommand can be used to delete files that aren't referenced by any cached manifest."),
+            )
+            .arg(
+                Arg::new("protect")
+                    .long("protect")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .global(true)
+                    .validator(pattern::validate)
+                    .value_name("PATTERN")
+                    .help("A pattern describing paths that are never pruned")
+                    .long_help("A pattern describing paths that are never pruned. Patterns use the gitignore format and are relative to the cache. Patterns can also be provided in a .rustdownignore file in the cache and patterns in arguments take precedence over them."),
+            )
+            .arg(
+                Arg::new("paranoid")
+                    .long("paranoid")
+                    .help("Rehashes every cached archive")
+                    .long_help("Rehashes every cached archive. Otherwise, the checksums of cached archives are kept in an index and archives are only rehashed if their size or modification time has changed."),
+            )
+            .arg(
+                Arg::new("blobs")
+                    .long("blobs")
+                    .takes_value(true)
+                    .possible_values(["hard", "symbolic"])
+                    .value_name("LINK")
+                    .help("Keeps each archive once in a blob store that's linked into the dist tree")
+                    .long_help("Keeps each archive once in a blob store that's linked into the dist tree. Archives are kept in the blobs/sha256 directory of the cache by checksum and the dist tree is built out of hard or symbolic links to them. Blobs are deleted when no cached archive refers to them. Archives without a checksum are kept in the dist tree."),
             )
             .arg(
                 Arg::new("jobs")
@@ -75,9 +318,21 @@ impl<'a> Parser<'a> {
                     .long("jobs")
                     .takes_value(true)
                     .default_value(ncpus)
+                    .global(true)
                     .validator(NonZeroUsize::from_str)
                     .help("The number of jobs that can run in parallel"),
             )
+            .arg(
+                Arg::new("wait")
+                    .long("wait")
+                    .takes_value(true)
+                    .default_value("0")
+                    .global(true)
+                    .validator(parse_seconds)
+                    .value_name("SECONDS")
+                    .help("The maximum time to wait for another process to release the cache")
+                    .long_help("The maximum time to wait for another process to release the cache. A cache is locked while it's being changed so that concurrent runs don't interfere with each other. Locks left behind by processes that have crashed are recovered."),
+            )
             .arg(
                 Arg::new("log_level")
                     .short('l')
@@ -85,7 +340,30 @@ impl<'a> Parser<'a> {
                     .takes_value(true)
                     .possible_values(["trace", "debug", "info", "warn", "error"])
                     .default_value("info")
+                    .global(true)
                     .help("The log level"),
+            )
+            .arg(
+                Arg::new("check")
+                    .long("check")
+                    .help("Reports unknown manifest fields without building the cache")
+                    .long_help("Reports unknown manifest fields without building the cache. Unknown fields are preserved in normalised manifests but files that they refer to aren't cached so their URLs are reported and left untouched. The report is printed to stdout regardless of the log level."),
+            )
+            .arg(
+                Arg::new("dry_run")
+                    .long("dry-run")
+                    .conflicts_with("check")
+                    .help("Reports the changes that building the cache would make without making them")
+                    .long_help("Reports the changes that building the cache would make without making them. Manifests are fetched so that the archives can be determined but nothing is written or downloaded. The plan is printed to stdout regardless of the log level."),
+            )
+            .arg(
+                Arg::new("snapshots")
+                    .long("snapshots")
+                    .takes_value(true)
+                    .validator(NonZeroUsize::from_str)
+                    .value_name("COUNT")
+                    .help("Publishes the cache as snapshots and keeps the COUNT newest snapshots")
+                    .long_help("Publishes the cache as snapshots and keeps the COUNT newest snapshots. Each build creates a snapshot that hard links unchanged files from the current snapshot and then atomically swaps the current symbolic link in the cache to point to it. The current symbolic link is used as the default host. Dry runs report the changes that the next snapshot would make to the current snapshot."),
             );
 
         Self { command }
@@ -94,23 +372,106 @@ impl<'a> Parser<'a> {
     fn parse(&mut self, arguments: impl Iterator<Item = String>) -> Result<Arguments, clap::Error> {
         let matches = self.command.try_get_matches_from_mut(arguments)?;
 
-        let path = PathBuf::from(matches.value_of("path").expect("missing path"));
-        let host = match matches.value_of("host") {
-            Some(host) => Url::parse(host).expect("invalid host"),
-            None => Url::from_directory_path(&path).expect("invalid path"),
+        let (mode, matches) = match matches.subcommand() {
+            Some(("verify", matches)) => (
+                Mode::Verify(VerifyArguments {
+                    path: PathBuf::from(matches.value_of("path").expect("missing path")),
+                    json: matches.is_present("json"),
+                    protected: protected(matches),
+                }),
+                matches,
+            ),
+            Some(("prune", matches)) => (
+                Mode::Prune(PruneArguments {
+                    path: PathBuf::from(matches.value_of("path").expect("missing path")),
+                    protected: protected(matches),
+                }),
+                matches,
+            ),
+            _ => (Mode::Build(Box::new(self.parse_build(&matches)?)), &matches),
+        };
+
+        let jobs = NonZeroUsize::from_str(matches.value_of("jobs").expect("missing jobs"))
+            .expect("invalid jobs");
+
+        let log_level = Level::from_str(matches.value_of("log_level").expect("missing log level"))
+            .expect("invalid log level");
+
+        let wait =
+            parse_seconds(matches.value_of("wait").expect("missing wait")).expect("invalid wait");
+
+        Ok(Arguments {
+            mode,
+            jobs,
+            log_level,
+            wait,
+        })
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn parse_build(&self, matches: &ArgMatches) -> Result<BuildArguments, clap::Error> {
+        let mut positionals = matches
+            .values_of("channel")
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        let path = PathBuf::from(positionals.pop().expect("missing path"));
+        if Url::from_directory_path(&path).is_err() {
+            return Err(self.command.clone().error(ValueValidation, "invalid path"));
+        }
+
+        let snapshots = matches
+            .value_of("snapshots")
+            .map(|count| NonZeroUsize::from_str(count).expect("invalid snapshots"));
+
+        let host = match (matches.value_of("host"), snapshots) {
+            (Some(host), _) => Url::parse(host).expect("invalid host"),
+            (None, Some(_)) => {
+                Url::from_directory_path(path.join(CURRENT_LINK)).expect("invalid path")
+            }
+            (None, None) => Url::from_directory_path(&path).expect("invalid path"),
         };
 
+        let mut upstream = Url::parse(matches.value_of("upstream").expect("missing upstream"))
+            .expect("invalid upstream");
+
+        // The upstream URL is treated as a directory even if it doesn't have a trailing slash.
+        if !upstream.path().ends_with('/') {
+            upstream.set_path(&format!("{}/", upstream.path()));
+        }
+
+        let upstream_channels = positionals.into_iter().map(|channel| {
+            let channel = Channel::from_str(channel)
+                .map_err(|error| self.command.clone().error(ValueValidation, error))?;
+
+            let url = upstream
+                .join(&channel.relative_manifest_path())
+                .expect("invalid manifest url");
+
+            Ok::<_, clap::Error>((channel, url))
+        });
+
         let channels = matches
             .grouped_values_of("manifest")
-            .expect("missing manifest")
             .into_iter()
+            .flatten()
             .map(IntoIterator::into_iter)
             .map(|mut group| {
-                let path = PathBuf::from(group.next().ok_or_else(|| {
+                let path = group.next().ok_or_else(|| {
                     self.command
                         .clone()
                         .error(TooFewValues, "missing manifest path")
-                })?);
+                })?;
+
+                let url = path::absolute(path)
+                    .ok()
+                    .and_then(|path| Url::from_file_path(path).ok())
+                    .ok_or_else(|| {
+                        self.command
+                            .clone()
+                            .error(ValueValidation, "invalid manifest path")
+                    })?;
 
                 let channel = Channel::from_str(group.next().ok_or_else(|| {
                     self.command
@@ -119,12 +480,13 @@ impl<'a> Parser<'a> {
                 })?)
                 .map_err(|error| self.command.clone().error(ValueValidation, error))?;
 
-                Ok::<_, clap::Error>((channel, path))
+                Ok::<_, clap::Error>((channel, url))
             })
+            .chain(upstream_channels)
             .try_fold(AHashMap::new(), |mut map, pair| {
-                let (channel, path) = pair?;
+                let (channel, url) = pair?;
 
-                if map.insert(channel, path).is_some() {
+                if map.insert(channel, url).is_some() {
                     return Err(self
                         .command
                         .clone()
@@ -134,51 +496,414 @@ impl<'a> Parser<'a> {
                 Ok(map)
             })?;
 
-        let jobs = NonZeroUsize::from_str(matches.value_of("jobs").expect("missing jobs"))
-            .expect("invalid jobs");
+        if channels.is_empty() {
+            return Err(self.command.clone().error(TooFewValues, "missing channel"));
+        }
 
-        let log_level = Level::from_str(matches.value_of("log_level").expect("missing log level"))
-            .expect("invalid log level");
+        let keyring = matches
+            .value_of("keyring")
+            .map(|path| {
+                Keyring::from_file(Path::new(path)).map_err(|error| {
+                    self.command
+                        .clone()
+                        .error(ValueValidation, format!("invalid keyring: {error}"))
+                })
+            })
+            .transpose()?;
 
-        Ok(Arguments {
+        let verification = Verification {
+            checksums: matches.is_present("verify_checksums"),
+            keyring,
+        };
+
+        let selection = Selection {
+            targets: matches
+                .values_of("target")
+                .map(|targets| targets.map(String::from).collect()),
+            packages: matches
+                .values_of("package")
+                .map(|packages| packages.map(String::from).collect()),
+            profile: matches.value_of("profile").map(String::from),
+            compressions: matches.values_of("compression").map(|compressions| {
+                compressions
+                    .map(|compression| {
+                        Compression::from_str(compression).expect("invalid compression")
+                    })
+                    .collect()
+            }),
+        };
+
+        let retry = Retry {
+            attempts: NonZeroUsize::from_str(
+                matches.value_of("attempts").expect("missing attempts"),
+            )
+            .expect("invalid attempts"),
+            backoff: parse_seconds(matches.value_of("backoff").expect("missing backoff"))
+                .expect("invalid backoff"),
+            jitter: parse_seconds(matches.value_of("jitter").expect("missing jitter"))
+                .expect("invalid jitter"),
+        };
+
+        let link = matches.is_present("hard_link");
+
+        let signer = matches
+            .value_of("signing_key")
+            .map(|path| {
+                Signer::from_file(Path::new(path)).map_err(|error| {
+                    self.command
+                        .clone()
+                        .error(ValueValidation, format!("invalid signing key: {error}"))
+                })
+            })
+            .transpose()?;
+
+        let options = Options {
+            archive_checksums: matches.is_present("archive_checksums"),
+            signer,
+            additive: matches.is_present("no_prune"),
+            protected: protected(matches),
+            paranoid: matches.is_present("paranoid"),
+            blobs: matches.value_of("blobs").map(|link| match link {
+                "hard" => Link::Hard,
+                "symbolic" => Link::Symbolic,
+                _ => unreachable!(),
+            }),
+        };
+
+        let check = matches.is_present("check");
+        let dry_run = matches.is_present("dry_run");
+
+        Ok(BuildArguments {
             path,
             host,
             channels,
-            jobs,
-            log_level,
+            verification,
+            selection,
+            retry,
+            link,
+            options,
+            check,
+            dry_run,
+            snapshots,
         })
     }
 }
 
+async fn build(arguments: BuildArguments, jobs: NonZeroUsize, wait: Duration) -> Result<()> {
+    let downloader = Downloader::new(FileDownloader::new(arguments.link), arguments.retry);
+    if arguments.check {
+        let manifests = Cache::fetch_manifests(
+            &arguments.channels,
+            &arguments.verification,
+            &downloader,
+            jobs,
+        )
+        .await?;
+
+        // The report is printed to stdout so that it's shown regardless of the log level.
+        for (channel, manifest) in manifests.iter().sorted_by_key(|(channel, _)| *channel) {
+            for key in manifest.unknown_keys() {
+                println!("{channel}: unknown field {key}");
+            }
+
+            for (key, url) in manifest.unknown_urls() {
+                println!("{channel}: unknown field {key} refers to {url} which isn't cached");
+            }
+        }
+
+        info!("checked manifests");
+        return Ok(());
+    }
+
+    // Dry runs don't change the cache so they don't need to lock it.
+    let _lock = if arguments.dry_run {
+        None
+    } else {
+        Some(Lock::acquire(&arguments.path, wait).await?)
+    };
+
+    // Building a cache that publishes snapshots in place would prune its snapshots.
+    if arguments.snapshots.is_none() && snapshot::is_published(&arguments.path).await? {
+        bail!("cache publishes snapshots so the snapshots argument must be provided");
+    }
+
+    // Snapshots are built in their own directory and published once they're complete.
+    let snapshots = arguments
+        .snapshots
+        .map(|retention| Snapshots::new(arguments.path.clone(), retention));
+
+    let path = match &snapshots {
+        // Dry runs plan against the current snapshot that the next snapshot would be built from.
+        Some(_) if arguments.dry_run => snapshot::current(&arguments.path)
+            .await?
+            .unwrap_or(arguments.path),
+        Some(snapshots) => snapshots.prepare().await?,
+        None => arguments.path,
+    };
+
+    let cache = Cache::new(path, arguments.host, arguments.options);
+    if arguments.dry_run {
+        let plan = cache
+            .plan(
+                &arguments.channels,
+                &arguments.verification,
+                &arguments.selection,
+                &downloader,
+                jobs,
+            )
+            .await?;
+
+        print_plan(&plan);
+        return Ok(());
+    }
+
+    cache
+        .build(
+            &arguments.channels,
+            &arguments.verification,
+            &arguments.selection,
+            &downloader,
+            jobs,
+        )
+        .await?;
+
+    info!("built cache");
+    if let Some(snapshots) = snapshots {
+        let snapshot = snapshots.publish().await?;
+        info!(
+            snapshot = snapshot.to_string_lossy().as_ref(),
+            "published snapshot"
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints a plan to stdout so that it's shown regardless of the log level.
+fn print_plan(plan: &Plan) {
+    let mut unknown = 0;
+    for (file, bytes) in &plan.download {
+        if let Some(bytes) = bytes {
+            println!("would download {} ({bytes} bytes)", file.display());
+        } else {
+            unknown += 1;
+            println!("would download {} (unknown size)", file.display());
+        }
+    }
+
+    let known = plan.download.values().flatten().sum::<u64>();
+    let mut summaries = vec![if unknown == 0 {
+        format!(
+            "would download {} files ({known} bytes)",
+            plan.download.len()
+        )
+    } else {
+        format!(
+            "would download {} files (at least {known} bytes, {unknown} files of unknown size)",
+            plan.download.len()
+        )
+    }];
+
+    for (files, action) in [
+        (&plan.hash, "would hash"),
+        (&plan.delete, "would delete"),
+        (&plan.skip, "would skip"),
+        (&plan.write, "would write"),
+    ] {
+        for (file, bytes) in files {
+            println!("{action} {} ({bytes} bytes)", file.display());
+        }
+
+        summaries.push(format!(
+            "{action} {} files ({} bytes)",
+            files.len(),
+            files.values().sum::<u64>()
+        ));
+    }
+
+    for summary in summaries {
+        println!("{summary}");
+    }
+}
+
+async fn verify(arguments: VerifyArguments, jobs: NonZeroUsize) -> Result<()> {
+    // The current snapshot is verified if the cache publishes snapshots.
+    let path = snapshot::current(&arguments.path)
+        .await?
+        .unwrap_or(arguments.path);
+
+    let host = Url::from_directory_path(path::absolute(&path)?).expect("invalid path");
+    let options = Options {
+        protected: arguments.protected,
+        ..Options::default()
+    };
+
+    let cache = Cache::new(path, host, options);
+    let report = cache.verify(jobs).await?;
+
+    if arguments.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for (files, problem) in [
+            (&report.missing, "missing file"),
+            (&report.corrupt, "corrupt file"),
+            (&report.unreferenced, "unreferenced file"),
+        ] {
+            for file in files {
+                warn!(file = file.to_string_lossy().as_ref(), "{problem}");
+            }
+        }
+
+        if report.empty {
+            warn!("cache doesn't contain any manifests");
+        }
+    }
+
+    if !report.is_empty() {
+        bail!("found {} problems", report.len());
+    }
+
+    info!("verified cache");
+    Ok(())
+}
+
+async fn prune(arguments: PruneArguments, wait: Duration) -> Result<()> {
+    let host = Url::from_directory_path(path::absolute(&arguments.path)?).expect("invalid path");
+    let options = Options {
+        protected: arguments.protected,
+        ..Options::default()
+    };
+
+    let _lock = Lock::acquire(&arguments.path, wait).await?;
+
+    // The current snapshot is pruned if the cache publishes snapshots. Other snapshots are kept as
+    // they were published so that they can be rolled back to.
+    let path = snapshot::current(&arguments.path)
+        .await?
+        .unwrap_or(arguments.path);
+
+    let cache = Cache::new(path, host, options);
+    cache.prune_unreferenced().await?;
+
+    info!("pruned cache");
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let ncpus = num_cpus::get();
     let arguments = Parser::new(&ncpus.to_string())
-        .parse(env::args().into_iter())
+        .parse(env::args())
         .map_err(|error| error.exit())
         .expect("unhandled error");
 
+    // Logs are written to stderr so that they're never mixed with reports.
     tracing_subscriber::fmt()
         .with_max_level(arguments.log_level)
+        .with_writer(io::stderr)
         .init();
 
-    let channels = stream::iter(arguments.channels.into_iter())
-        .map(|(channel, path)| async {
-            let mut file = File::open(path).await?;
-            let mut bytes = Vec::new();
-            file.read_to_end(&mut bytes).await?;
-            Ok::<_, eyre::Error>((channel, Manifest::from_slice(&bytes)?))
-        })
-        .map(Ok)
-        .try_buffer_unordered(arguments.jobs.get())
-        .try_collect::<AHashMap<Channel, Manifest>>()
-        .await?;
+    match arguments.mode {
+        Mode::Build(build_arguments) => {
+            build(*build_arguments, arguments.jobs, arguments.wait).await
+        }
+        Mode::Verify(verify_arguments) => verify(verify_arguments, arguments.jobs).await,
+        Mode::Prune(prune_arguments) => prune(prune_arguments, arguments.wait).await,
+    }
+}
 
-    let cache = Cache::new(arguments.path, arguments.host);
-    cache
-        .build(&channels, &Downloader::default(), arguments.jobs)
-        .await?;
+#[cfg(test)]
+mod tests {
+    use super::{Channel, Mode, Parser};
+    use std::{num::NonZeroUsize, str::FromStr};
 
-    info!("built cache");
-    Ok(())
+    fn parse(arguments: &[&str]) -> Result<Mode, clap::Error> {
+        Parser::new("1")
+            .parse(
+                ["rustdown"]
+                    .iter()
+                    .chain(arguments)
+                    .map(ToString::to_string),
+            )
+            .map(|arguments| arguments.mode)
+    }
+
+    #[test]
+    fn channels_before_path() {
+        let Mode::Build(arguments) = parse(&["stable:1.60.0", "nightly:2022-04-01", "/cache"])
+            .expect("failed to parse arguments")
+        else {
+            panic!("expected build arguments");
+        };
+
+        assert_eq!(arguments.path.to_str(), Some("/cache"));
+        assert_eq!(arguments.channels.len(), 2);
+    }
+
+    #[test]
+    fn options_between_positionals() {
+        let Mode::Build(arguments) = parse(&[
+            "stable:1.60.0",
+            "--target",
+            "x86_64-unknown-linux-gnu",
+            "-l",
+            "warn",
+            "nightly:2022-04-01",
+            "--dry-run",
+            "/cache",
+        ])
+        .expect("failed to parse arguments") else {
+            panic!("expected build arguments");
+        };
+
+        assert_eq!(arguments.path.to_str(), Some("/cache"));
+        assert!(arguments.dry_run);
+        assert!(arguments
+            .selection
+            .targets
+            .expect("missing targets")
+            .contains("x86_64-unknown-linux-gnu"));
+
+        for channel in ["stable:1.60.0", "nightly:2022-04-01"] {
+            let channel = Channel::from_str(channel).expect("invalid channel");
+            assert!(arguments.channels.contains_key(&channel));
+        }
+    }
+
+    #[test]
+    fn manifest_without_channel() {
+        let Mode::Build(arguments) =
+            parse(&["--manifest", "/manifest.toml", "stable:1.60.0", "/cache"])
+                .expect("failed to parse arguments")
+        else {
+            panic!("expected build arguments");
+        };
+
+        assert_eq!(arguments.path.to_str(), Some("/cache"));
+        assert_eq!(arguments.channels.len(), 1);
+    }
+
+    #[test]
+    fn dry_run_with_snapshots() {
+        let Mode::Build(arguments) =
+            parse(&["--dry-run", "--snapshots", "3", "stable:1.60.0", "/cache"])
+                .expect("failed to parse arguments")
+        else {
+            panic!("expected build arguments");
+        };
+
+        assert!(arguments.dry_run);
+        assert_eq!(arguments.snapshots.map(NonZeroUsize::get), Some(3));
+    }
+
+    #[test]
+    fn missing_channel() {
+        assert!(parse(&["/cache"]).is_err());
+    }
+
+    #[test]
+    fn subcommands() {
+        assert!(matches!(parse(&["verify", "/cache"]), Ok(Mode::Verify(_))));
+        assert!(matches!(parse(&["prune", "/cache"]), Ok(Mode::Prune(_))));
+    }
 }
@@ -0,0 +1,100 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::Path;
+
+/// Validates a pattern describing paths relative to the root of a cache.
+pub fn validate(pattern: &str) -> Result<(), ignore::Error> {
+    Patterns::new([pattern]).map(|_| ())
+}
+
+/// A set of patterns describing paths relative to the root of a cache.
+///
+/// Patterns follow the format of gitignore files. A pattern without a separator (other than a
+/// trailing one) matches a name at any depth while other patterns are anchored to the root. A
+/// pattern with a trailing separator only matches directories and a pattern that begins with `!`
+/// negates an earlier pattern. Empty lines and lines that begin with `#` are skipped.
+#[derive(Clone, Debug)]
+pub struct Patterns(Gitignore);
+
+impl Patterns {
+    /// Creates a set from `lines`. Later lines take precedence over earlier lines.
+    pub fn new<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<Self, ignore::Error> {
+        let mut builder = GitignoreBuilder::new("");
+        for line in lines {
+            builder.add_line(None, line)?;
+        }
+
+        Ok(Self(builder.build()?))
+    }
+
+    /// Returns whether or not `path` or one of its ancestors matches a pattern. `is_dir` describes
+    /// whether or not `path` is a directory.
+    #[must_use]
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        !path.as_os_str().is_empty() && self.0.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Patterns;
+    use std::path::Path;
+
+    fn matches(patterns: &[&str], path: &str, is_dir: bool) -> bool {
+        Patterns::new(patterns.iter().copied())
+            .expect("invalid patterns")
+            .matches(Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn directory_patterns() {
+        let patterns = [".well-known/"];
+        assert!(matches(&patterns, ".well-known", true));
+        assert!(matches(
+            &patterns,
+            ".well-known/acme-challenge/token",
+            false
+        ));
+        assert!(matches(&patterns, "nested/.well-known/token", false));
+        assert!(!matches(&patterns, ".well-known", false));
+    }
+
+    #[test]
+    fn nested_directories() {
+        let patterns = ["static/docs"];
+        assert!(matches(&patterns, "static/docs", true));
+        assert!(matches(&patterns, "static/docs/index.html", false));
+        assert!(!matches(&patterns, "dist/static/docs/index.html", false));
+
+        let patterns = ["**/docs"];
+        assert!(matches(&patterns, "dist/static/docs/index.html", false));
+    }
+
+    #[test]
+    fn names_match_at_any_depth() {
+        let patterns = ["rustup-init*"];
+        assert!(matches(&patterns, "rustup-init.sh", false));
+        assert!(matches(&patterns, "rustup/dist/rustup-init", false));
+        assert!(!matches(&patterns, "dist/rustc.tar.xz", false));
+    }
+
+    #[test]
+    fn anchored_patterns() {
+        let patterns = ["/index.html"];
+        assert!(matches(&patterns, "index.html", false));
+        assert!(!matches(&patterns, "dist/index.html", false));
+    }
+
+    #[test]
+    fn negated_patterns() {
+        let patterns = ["*.html", "!dist/*.html"];
+        assert!(matches(&patterns, "index.html", false));
+        assert!(!matches(&patterns, "dist/index.html", false));
+    }
+
+    #[test]
+    fn comments_and_empty_lines() {
+        let patterns = ["# index.html", "", "\\#notes"];
+        assert!(!matches(&patterns, "index.html", false));
+        assert!(matches(&patterns, "#notes", false));
+    }
+}
@@ -0,0 +1,216 @@
+use pgp::{
+    composed::{ArmorOptions, Deserializable, DetachedSignature, SignedPublicKey, SignedSecretKey},
+    crypto::hash::HashAlgorithm,
+    types::Password,
+};
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, Cursor},
+    path::Path,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    FileSystem(io::Error),
+    // The error is boxed because it's significantly larger than the other variants.
+    Pgp(Box<pgp::errors::Error>),
+    UnknownSigner,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileSystem(error) => error.fmt(f),
+            Self::Pgp(error) => error.fmt(f),
+            Self::UnknownSigner => write!(f, "signature was not made by a key in the keyring"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::FileSystem(error) => error.source(),
+            Self::Pgp(error) => error.source(),
+            Self::UnknownSigner => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::FileSystem(error)
+    }
+}
+
+impl From<pgp::errors::Error> for Error {
+    fn from(error: pgp::errors::Error) -> Self {
+        Self::Pgp(Box::new(error))
+    }
+}
+
+/// A keyring is a collection of trusted public keys that can be used to verify signatures.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    keys: Vec<SignedPublicKey>,
+}
+
+impl Keyring {
+    /// Reads a keyring from a file containing one or more public keys. The keys can be either
+    /// binary or ASCII armoured.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let bytes = std::fs::read(path)?;
+        let (keys, _) = SignedPublicKey::from_reader_many(Cursor::new(bytes))?;
+        Ok(Self {
+            keys: keys.collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Verifies that `signature` is a valid detached signature of `content` made by a key (or
+    /// subkey) in the keyring. The signature can be either binary or ASCII armoured.
+    pub fn verify(&self, content: &[u8], signature: &[u8]) -> Result<(), Error> {
+        let (signature, _) = DetachedSignature::from_reader_single(Cursor::new(signature))?;
+        let verified = self.keys.iter().any(|key| {
+            signature.verify(key, content).is_ok()
+                || key
+                    .public_subkeys
+                    .iter()
+                    .any(|subkey| signature.verify(subkey, content).is_ok())
+        });
+
+        if verified {
+            Ok(())
+        } else {
+            Err(Error::UnknownSigner)
+        }
+    }
+}
+
+/// A signer creates detached signatures using a secret key.
+#[derive(Clone, Debug)]
+pub struct Signer {
+    key: SignedSecretKey,
+}
+
+impl Signer {
+    /// Reads a signer from a file containing a secret key that isn't protected by a passphrase.
+    /// The key can be either binary or ASCII armoured.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let bytes = std::fs::read(path)?;
+        let (key, _) = SignedSecretKey::from_reader_single(Cursor::new(bytes))?;
+        Ok(Self { key })
+    }
+
+    /// Returns an ASCII armoured detached signature of `content`.
+    ///
+    /// The first subkey that is capable of signing is used. Otherwise, the primary key is used.
+    pub fn sign(&self, content: &[u8]) -> Result<Vec<u8>, Error> {
+        let subkey = self.key.secret_subkeys.iter().find(|subkey| {
+            subkey
+                .signatures
+                .iter()
+                .any(|signature| signature.key_flags().sign())
+        });
+
+        let signature = match subkey {
+            Some(subkey) => DetachedSignature::sign_binary_data(
+                rand::thread_rng(),
+                &subkey.key,
+                &Password::empty(),
+                HashAlgorithm::Sha256,
+                content,
+            ),
+            None => DetachedSignature::sign_binary_data(
+                rand::thread_rng(),
+                &self.key.primary_key,
+                &Password::empty(),
+                HashAlgorithm::Sha256,
+                content,
+            ),
+        }?;
+
+        Ok(signature.to_armored_bytes(ArmorOptions::default())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Keyring, Signer};
+    use std::path::{Path, PathBuf};
+
+    const MANIFEST: &[u8] = include_bytes!("../tests/fixtures/channel-rust-1.60.0.toml");
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn keyring(name: &str) -> Keyring {
+        Keyring::from_file(&fixture(name)).expect("failed to read keyring")
+    }
+
+    fn signature(name: &str) -> Vec<u8> {
+        std::fs::read(fixture(name)).expect("failed to read signature")
+    }
+
+    #[test]
+    fn good_signature() {
+        keyring("test-key.pub.asc")
+            .verify(MANIFEST, &signature("channel-rust-1.60.0.toml.asc"))
+            .expect("signature should be trusted");
+    }
+
+    #[test]
+    fn subkey_signature() {
+        keyring("test-key.pub.asc")
+            .verify(MANIFEST, &signature("channel-rust-1.60.0.toml.subkey.asc"))
+            .expect("signature should be trusted");
+    }
+
+    #[test]
+    fn bad_signature() {
+        let mut manifest = MANIFEST.to_vec();
+        manifest.extend_from_slice(b"\n");
+
+        assert!(matches!(
+            keyring("test-key.pub.asc")
+                .verify(&manifest, &signature("channel-rust-1.60.0.toml.asc")),
+            Err(Error::UnknownSigner)
+        ));
+    }
+
+    #[test]
+    fn untrusted_signature() {
+        assert!(matches!(
+            keyring("test-key.pub.asc").verify(
+                MANIFEST,
+                &signature("channel-rust-1.60.0.toml.untrusted.asc")
+            ),
+            Err(Error::UnknownSigner)
+        ));
+    }
+
+    #[test]
+    fn malformed_signature() {
+        assert!(matches!(
+            keyring("test-key.pub.asc").verify(MANIFEST, b"not a signature"),
+            Err(Error::Pgp(_))
+        ));
+    }
+
+    #[test]
+    fn sign_round_trip() {
+        let signer = Signer::from_file(&fixture("test-key.sec.asc")).expect("failed to read key");
+        let signature = signer.sign(MANIFEST).expect("failed to sign");
+
+        keyring("test-key.pub.asc")
+            .verify(MANIFEST, &signature)
+            .expect("signature should be trusted");
+
+        assert!(matches!(
+            keyring("untrusted-key.pub.asc").verify(MANIFEST, &signature),
+            Err(Error::UnknownSigner)
+        ));
+    }
+}
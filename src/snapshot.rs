@@ -0,0 +1,164 @@
+use crate::{
+    cache::{INDEX_FILE, STATE_DIRECTORY},
+    extension::Path as PathExtension,
+};
+use chrono::Utc;
+use std::{
+    io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    process,
+};
+use tokio::{fs, task};
+use walkdir::WalkDir;
+
+/// The directory, relative to the root, where snapshots are kept.
+pub const SNAPSHOT_DIRECTORY: &str = "snapshots";
+
+/// The directory, relative to the snapshot directory, where the next snapshot is built.
+const NEXT_SNAPSHOT: &str = ".next";
+
+/// The symbolic link, relative to the root, that points to the current snapshot.
+pub const CURRENT_LINK: &str = "current";
+
+/// Hard links every file in the directory at `source` into the directory at `destination`.
+///
+/// Symbolic links are not carried over. The state directory isn't linked because its files are
+/// modified in place (eg. partial downloads are resumed) but the index is copied so that unchanged
+/// archives aren't rehashed.
+fn link_tree(source: &Path, destination: &Path) -> Result<(), io::Error> {
+    let entries = WalkDir::new(source)
+        .into_iter()
+        .filter_entry(|entry| entry.path() != source.join(STATE_DIRECTORY));
+
+    for entry in entries {
+        let entry = entry?;
+        let target = destination.join(
+            entry
+                .path()
+                .strip_prefix(source)
+                .expect("path outside snapshot"),
+        );
+
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(target)?;
+        } else if entry.file_type().is_file() {
+            std::fs::hard_link(entry.path(), target)?;
+        }
+    }
+
+    let index = source.join(INDEX_FILE);
+    if index.try_exists()? {
+        let target = destination.join(INDEX_FILE);
+        std::fs::create_dir_all(target.parent().expect("file has no parent"))?;
+        std::fs::copy(index, target)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the path of the current snapshot of the cache at `root` or `None` if there isn't one.
+pub async fn current(root: &Path) -> Result<Option<PathBuf>, io::Error> {
+    match fs::read_link(root.join(CURRENT_LINK)).await {
+        Ok(target) => Ok(Some(root.join(target))),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Returns whether or not the cache at `root` publishes snapshots.
+pub async fn is_published(root: &Path) -> Result<bool, io::Error> {
+    Ok(root.join(SNAPSHOT_DIRECTORY).async_try_exists().await? || current(root).await?.is_some())
+}
+
+/// Publishes generations of a cache as snapshots.
+///
+/// Each generation is built in a new snapshot that starts with hard links to the files of the
+/// current snapshot. Once it's complete, a symbolic link is atomically swapped to point to it so that
+/// readers never see a mix of generations.
+#[derive(Debug)]
+pub struct Snapshots {
+    path: PathBuf,
+    retention: NonZeroUsize,
+}
+
+impl Snapshots {
+    /// Creates a set of snapshots at `path` that keeps the `retention` newest snapshots.
+    #[must_use]
+    pub fn new(path: PathBuf, retention: NonZeroUsize) -> Self {
+        Self { path, retention }
+    }
+
+    /// Returns the path where the next snapshot is built.
+    ///
+    /// The next snapshot starts with hard links to the files of the current snapshot. If a previous
+    /// build was interrupted then its snapshot is reused so that it can be resumed.
+    pub async fn prepare(&self) -> Result<PathBuf, io::Error> {
+        let next = self.path.join(SNAPSHOT_DIRECTORY).join(NEXT_SNAPSHOT);
+        if next.async_try_exists().await? {
+            return Ok(next);
+        }
+
+        match current(&self.path).await? {
+            Some(current) => {
+                let destination = next.clone();
+                task::spawn_blocking(move || link_tree(&current, &destination))
+                    .await
+                    .expect("panicked while linking snapshot")?;
+            }
+            None => fs::create_dir_all(&next).await?,
+        }
+
+        Ok(next)
+    }
+
+    /// Publishes the next snapshot and returns its path.
+    ///
+    /// Snapshots that are older than the newest snapshots being kept are deleted.
+    pub async fn publish(&self) -> Result<PathBuf, io::Error> {
+        let directory = self.path.join(SNAPSHOT_DIRECTORY);
+        let name = Utc::now().format("%Y%m%dT%H%M%S%.6fZ").to_string();
+        fs::rename(directory.join(NEXT_SNAPSHOT), directory.join(&name)).await?;
+
+        // The symbolic link is relative so that the root can be moved.
+        let link = self.path.join(CURRENT_LINK);
+        let temporary = self
+            .path
+            .join(format!(".{CURRENT_LINK}.{}.tmp", process::id()));
+        let target = Path::new(SNAPSHOT_DIRECTORY).join(&name);
+
+        // A temporary link might be left behind if a previous publication was interrupted.
+        temporary.remove_file_if_exists().await?;
+
+        #[cfg(unix)]
+        fs::symlink(&target, &temporary).await?;
+        #[cfg(windows)]
+        fs::symlink_dir(&target, &temporary).await?;
+
+        fs::rename(&temporary, &link).await?;
+        self.path.sync_directory().await?;
+
+        self.retain().await?;
+        Ok(directory.join(name))
+    }
+
+    /// Deletes snapshots that are older than the newest snapshots being kept.
+    async fn retain(&self) -> Result<(), io::Error> {
+        let mut names = Vec::new();
+        let mut entries = fs::read_dir(self.path.join(SNAPSHOT_DIRECTORY)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if !name.to_string_lossy().starts_with('.') {
+                names.push(name);
+            }
+        }
+
+        // Snapshots are named after the time that they're published so they're sorted by age.
+        names.sort_unstable_by(|a, b| b.cmp(a));
+        for name in names.into_iter().skip(self.retention.get()) {
+            fs::remove_dir_all(self.path.join(SNAPSHOT_DIRECTORY).join(name)).await?;
+        }
+
+        Ok(())
+    }
+}